@@ -0,0 +1,281 @@
+use crate::config::Config;
+use std::collections::BTreeSet;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The result of checking one config.toml setting against this machine.
+pub struct Check {
+    pub what: String,
+    /// Why the check failed, or None if it passed.
+    pub problem: Option<String>,
+    /// Something worth knowing about a check that passed.
+    pub note: Option<String>,
+}
+
+impl Check {
+    fn new(what: &str, result: Result<(), String>) -> Self {
+        Check {
+            what: what.to_owned(),
+            problem: result.err(),
+            note: None,
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problem.is_none()
+    }
+}
+
+/// Check that the paths and profiles in `conf` are usable on this machine.
+/// Profiles are looked up in the aws cli config at `aws_config`.
+pub fn check_config(conf: &Config, aws_config: &Path) -> Vec<Check> {
+    let terraforming = Path::new(&conf.terraforming_path);
+    let deployments = Path::new(&conf.kubernetes_deployments_path);
+    let mut checks = vec![
+        Check::new(
+            "terraforming_path is a git checkout",
+            git_checkout(terraforming),
+        ),
+        Check::new(
+            "terraforming_path has projects/kubernetes-tectonic",
+            directory(&terraforming.join("projects/kubernetes-tectonic")),
+        ),
+        Check::new(
+            "terraforming_path has projects/kubernetes-ingress",
+            directory(&terraforming.join("projects/kubernetes-ingress")),
+        ),
+        Check::new(
+            "kubernetes_deployments_path is a git checkout",
+            git_checkout(deployments),
+        ),
+        Check::new(
+            "kubernetes_deployments_path has charts/pp-argo-cd",
+            directory(&deployments.join("charts/pp-argo-cd")),
+        ),
+        Check::new(
+            "kubernetes_deployments_ssh_key is readable only by you",
+            private_key(Path::new(&conf.kubernetes_deployments_ssh_key)),
+        ),
+        Check::new(
+            "keybase_secure_manifests_path is mounted",
+            mounted(Path::new(&conf.keybase_secure_manifests_path)),
+        ),
+    ];
+    let assets = Path::new(&conf.assets_cache_path);
+    let mut check = Check::new("assets_cache_path is writable", writable(assets));
+    if check.passed() && !assets.exists() {
+        check.note = Some("would be created".to_owned());
+    }
+    checks.push(check);
+
+    let profiles = aws_profiles(aws_config);
+    let mut wanted = vec![
+        ("infra_profile".to_owned(), conf.infra_profile.clone()),
+        ("v1_profile".to_owned(), conf.v1_profile.clone()),
+    ];
+    for (id, cluster) in &conf.clusters {
+        if let Some(profile) = &cluster.aws_profile {
+            wanted.push((format!("clusters.{}.aws_profile", id), profile.clone()));
+        }
+    }
+    for (setting, profile) in wanted {
+        let result = match &profiles {
+            Ok(profiles) if profiles.contains(&profile) => Ok(()),
+            Ok(_) => Err(format!(
+                "no profile {} in {}",
+                profile,
+                aws_config.display()
+            )),
+            Err(e) => Err(e.clone()),
+        };
+        let what = format!("{} is in the aws cli config", setting);
+        checks.push(Check::new(&what, result));
+    }
+    checks
+}
+
+/// One line per check, and a count of failures at the end.
+pub fn report(checks: &[Check]) -> String {
+    let mut out = String::new();
+    for check in checks {
+        match &check.problem {
+            None => match &check.note {
+                None => out.push_str(&format!("PASS  {}\n", check.what)),
+                Some(note) => out.push_str(&format!("PASS  {} ({})\n", check.what, note)),
+            },
+            Some(problem) => out.push_str(&format!("FAIL  {}: {}\n", check.what, problem)),
+        }
+    }
+    let failed = checks.iter().filter(|c| !c.passed()).count();
+    out.push_str(&format!(
+        "\n{} of {} checks passed\n",
+        checks.len() - failed,
+        checks.len()
+    ));
+    out
+}
+
+/// The aws cli config: `$AWS_CONFIG_FILE`, or `~/.aws/config`.
+pub fn aws_config_path(home: &Path) -> PathBuf {
+    match std::env::var_os("AWS_CONFIG_FILE") {
+        Some(path) => PathBuf::from(path),
+        None => home.join(".aws/config"),
+    }
+}
+
+/// Names of the profiles in an aws cli config, from `[profile name]` and
+/// `[default]` sections.
+pub fn aws_profiles(path: &Path) -> Result<BTreeSet<String>, String> {
+    let s = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let profiles = s
+        .lines()
+        .filter_map(|line| {
+            let section = line.trim().strip_prefix('[')?.strip_suffix(']')?.trim();
+            match section.strip_prefix("profile ") {
+                Some(name) => Some(name.trim().to_owned()),
+                None if section == "default" => Some(section.to_owned()),
+                None => None,
+            }
+        })
+        .collect();
+    Ok(profiles)
+}
+
+pub fn directory(path: &Path) -> Result<(), String> {
+    if path.is_dir() {
+        Ok(())
+    } else {
+        Err(format!("{} is not a directory", path.display()))
+    }
+}
+
+pub fn git_checkout(path: &Path) -> Result<(), String> {
+    directory(path)?;
+    // .git is a file in a worktree
+    if path.join(".git").exists() {
+        Ok(())
+    } else {
+        Err(format!("{} has no .git", path.display()))
+    }
+}
+
+fn private_key(path: &Path) -> Result<(), String> {
+    let meta = std::fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mode = meta.permissions().mode() & 0o777;
+    if mode == 0o600 {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} has mode {:o}; run chmod 600 {}",
+            path.display(),
+            mode,
+            path.display()
+        ))
+    }
+}
+
+/// Keybase mounts its filesystem at the path; until it does, the path is
+/// missing or empty.
+pub fn mounted(path: &Path) -> Result<(), String> {
+    directory(path)?;
+    let mut entries = std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if entries.next().is_some() {
+        Ok(())
+    } else {
+        Err(format!("{} is empty; is keybase running?", path.display()))
+    }
+}
+
+/// Whether `path` is a directory we can write to, or could be created as one.
+/// Nothing is created: a missing path is checked through the nearest
+/// directory above it that exists.
+pub fn writable(path: &Path) -> Result<(), String> {
+    let mut existing = path;
+    while !existing.exists() {
+        existing = match existing.parent() {
+            Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+            Some(parent) => parent,
+            None => return Err(format!("{} has no parent that exists", path.display())),
+        };
+    }
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    // creating an entry in a directory takes write and search permission
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        return Err(format!(
+            "{}: {}",
+            existing.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_check_config() {
+    let root = std::env::temp_dir().join(format!("clusterctl-check-{}", std::process::id()));
+    for dir in &[
+        "terraforming/.git",
+        "terraforming/projects/kubernetes-tectonic",
+        "terraforming/projects/kubernetes-ingress",
+        "kubernetes-deployments/.git",
+        "kubernetes-deployments/charts/pp-argo-cd",
+        "secure/secrets",
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    let key = root.join("id_rsa");
+    std::fs::write(&key, "key").unwrap();
+    std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600)).unwrap();
+    let aws_config = root.join("aws-config");
+    std::fs::write(
+        &aws_config,
+        "[default]\nregion = us-east-1\n\n[profile infra]\n[ profile v1 ]\n",
+    )
+    .unwrap();
+    let mut conf = crate::config::test_config();
+    let under = |dir: &str| root.join(dir).display().to_string();
+    conf.terraforming_path = under("terraforming");
+    conf.kubernetes_deployments_path = under("kubernetes-deployments");
+    conf.kubernetes_deployments_ssh_key = under("id_rsa");
+    conf.keybase_secure_manifests_path = under("secure");
+    conf.assets_cache_path = under("assets");
+
+    let checks = check_config(&conf, &aws_config);
+    assert!(checks.iter().all(Check::passed), "{}", report(&checks));
+    // the assets directory is only created when something is downloaded
+    assert!(report(&checks).contains("PASS  assets_cache_path is writable (would be created)\n"));
+    assert!(!root.join("assets").exists());
+
+    std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o644)).unwrap();
+    conf.v1_profile = "v1_power_user".to_owned();
+    let checks = check_config(&conf, &aws_config);
+    let failed: Vec<&str> = checks
+        .iter()
+        .filter(|c| !c.passed())
+        .map(|c| c.what.as_str())
+        .collect();
+    assert_eq!(
+        failed,
+        vec![
+            "kubernetes_deployments_ssh_key is readable only by you",
+            "v1_profile is in the aws cli config",
+        ]
+    );
+    assert!(report(&checks).ends_with("\n8 of 10 checks passed\n"));
+
+    // nothing can be created under a file
+    conf.assets_cache_path = format!("{}/assets", key.display());
+    let checks = check_config(&conf, &aws_config);
+    assert!(report(&checks).contains(&format!(
+        "FAIL  assets_cache_path is writable: {} is not a directory\n",
+        key.display()
+    )));
+    std::fs::remove_dir_all(&root).unwrap();
+}
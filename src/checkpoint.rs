@@ -0,0 +1,179 @@
+use anyhow::{Context, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    /// The step ran and the workflow moved on past it.
+    Succeeded,
+    /// The operator chose not to run the step.
+    Skipped,
+}
+
+impl std::fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            StepStatus::Succeeded => write!(f, "succeeded"),
+            StepStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub step: String,
+    pub prompt: String,
+    pub command: String,
+    pub status: StepStatus,
+    pub finished_at: DateTime<Utc>,
+}
+
+/// How far a workflow got on a cluster. Steps are identified by name and
+/// checked against their command line, so a workflow that changed since the
+/// checkpoint was written is not resumed past the change.
+///
+/// Stored at `assets_cache_path/<cluster_id>/checkpoints/<workflow>.json`. A
+/// run without `--resume` starts the checkpoint over.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub workflow: String,
+    pub steps: Vec<StepRecord>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    pub fn new<P: AsRef<Path>>(assets_cache_path: P, cluster_id: &str, workflow: &str) -> Self {
+        let path = assets_cache_path
+            .as_ref()
+            .join(cluster_id)
+            .join("checkpoints")
+            .join(format!("{}.json", workflow));
+        Checkpoint {
+            workflow: workflow.to_owned(),
+            steps: vec![],
+            path,
+        }
+    }
+
+    /// Load the checkpoint left by a previous attempt, or start an empty one.
+    pub fn load<P: AsRef<Path>>(
+        assets_cache_path: P,
+        cluster_id: &str,
+        workflow: &str,
+    ) -> Result<Self, Error> {
+        let empty = Checkpoint::new(assets_cache_path, cluster_id, workflow);
+        if !empty.path.exists() {
+            return Ok(empty);
+        }
+        let s = std::fs::read_to_string(&empty.path)?;
+        let mut checkpoint: Checkpoint = serde_json::from_str(&s)
+            .with_context(|| format!("malformed checkpoint {:?}", empty.path))?;
+        checkpoint.path = empty.path;
+        Ok(checkpoint)
+    }
+
+    /// The status of `step` from a previous attempt, if that attempt finished
+    /// it with the same command line.
+    pub fn status(&self, step: &str, command: &str) -> Option<StepStatus> {
+        self.steps
+            .iter()
+            .find(|s| s.step == step && s.command == command)
+            .map(|s| s.status)
+    }
+
+    /// Mark `step` finished, replacing whatever was recorded for it before.
+    pub fn mark(
+        &mut self,
+        step: &str,
+        prompt: &str,
+        command: &str,
+        status: StepStatus,
+    ) -> Result<(), Error> {
+        if self.status(step, command).is_some() {
+            return Ok(());
+        }
+        self.steps.retain(|s| s.step != step);
+        self.steps.push(StepRecord {
+            step: step.to_owned(),
+            prompt: prompt.to_owned(),
+            command: command.to_owned(),
+            status,
+            finished_at: Utc::now(),
+        });
+        self.save()
+    }
+
+    /// Forget every step except `steps`.
+    pub fn keep(&mut self, steps: &[String]) -> Result<(), Error> {
+        let before = self.steps.len();
+        self.steps.retain(|s| steps.contains(&s.step));
+        if self.steps.len() != before {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_checkpoint() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-checkpoint-{}", std::process::id()));
+    let mut c = Checkpoint::new(&dir, "development1", "argo-init");
+    c.mark(
+        "create-namespace",
+        "Execute?",
+        "kubectl create ns argocd",
+        StepStatus::Succeeded,
+    )
+    .unwrap();
+    c.mark(
+        "update-chart-deps",
+        "Execute?",
+        "helm dep update",
+        StepStatus::Skipped,
+    )
+    .unwrap();
+
+    let mut c = Checkpoint::load(&dir, "development1", "argo-init").unwrap();
+    assert_eq!(
+        c.status("create-namespace", "kubectl create ns argocd"),
+        Some(StepStatus::Succeeded)
+    );
+    assert_eq!(
+        c.status("update-chart-deps", "helm dep update"),
+        Some(StepStatus::Skipped)
+    );
+    // a step whose command changed does not count as done
+    assert_eq!(
+        c.status("update-chart-deps", "helm dep update --skip-refresh"),
+        None
+    );
+
+    // re-marking a done step keeps its original status
+    c.mark(
+        "create-namespace",
+        "Execute?",
+        "kubectl create ns argocd",
+        StepStatus::Skipped,
+    )
+    .unwrap();
+    assert_eq!(
+        c.status("create-namespace", "kubectl create ns argocd"),
+        Some(StepStatus::Succeeded)
+    );
+
+    c.keep(&["create-namespace".to_owned()]).unwrap();
+    let c = Checkpoint::load(&dir, "development1", "argo-init").unwrap();
+    assert_eq!(c.steps.len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,24 +1,802 @@
+use crate::policy::Policy;
 use anyhow::{anyhow, Context, Error};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
-use std::path::Path;
-use toml;
+use std::path::{Path, PathBuf};
+use toml::Value;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// The schema the config is written for; see `VERSION`.
+    #[serde(default = "current_version")]
+    pub version: u32,
     pub terraforming_path: String,
     pub kubernetes_deployments_path: String,
     pub keybase_secure_manifests_path: String,
+    #[serde(default = "default_revision")]
     pub kubernetes_deployments_revision: String,
     pub kubernetes_deployments_ssh_key: String,
     pub infra_profile: String,
     pub v1_profile: String,
     pub assets_cache_path: String,
+    /// The AWS region clusters run in.
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Whether argo-init skips TLS verification when talking to argocd,
+    /// whose ELB serves a self-signed certificate until it has a DNS name.
+    #[serde(default)]
+    pub argocd_insecure: bool,
+    /// Every cluster clusterctl may operate on, by cluster id.
+    #[serde(default)]
+    pub clusters: BTreeMap<String, Cluster>,
+    /// Rules terraform plans must follow, on top of those in the
+    /// terraforming repo.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub policy: Policy,
+    /// The directory config.toml was read from.
+    #[serde(skip)]
+    pub config_dir: PathBuf,
+    /// The layer each setting came from, by dotted key.
+    #[serde(skip)]
+    pub sources: BTreeMap<String, String>,
 }
 
+fn is_default(policy: &Policy) -> bool {
+    *policy == Policy::default()
+}
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
+}
+
+/// The config schema this clusterctl reads. Version 1 had no `version` key
+/// and no cluster inventory.
+pub const VERSION: u32 = 2;
+
+fn current_version() -> u32 {
+    VERSION
+}
+
+/// One cluster in the inventory, e.g.
+///
+/// ```toml
+/// [clusters.development1]
+/// environment = "development"
+/// namespaces = ["mars"]
+/// ```
+///
+/// Only `environment` is required; everything else has a default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Cluster {
+    /// `development` or `production`.
+    pub environment: String,
+    /// Defaults to the environment.
+    pub default_namespace: Option<String>,
+    /// Defaults to the top level `region`.
+    pub region: Option<String>,
+    /// The profile for aws cli and terraform commands. Defaults to
+    /// `infra_profile`.
+    pub aws_profile: Option<String>,
+    /// The S3 bucket the cluster's kubeconfig is in. Defaults to the bucket
+    /// whose name starts with `a<cluster id>`.
+    pub assets_bucket: Option<String>,
+    /// Namespaces created next to the default namespace.
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+    /// Defaults to the cluster id.
+    pub terraform_workspace: Option<String>,
+}
+
+/// Settings layered over config.toml, besides the files in config.d.
+#[derive(Debug, Default)]
+pub struct Layers {
+    /// The `[profiles.<name>]` tables to apply.
+    pub profile: Option<String>,
+    /// `CLUSTERCTL_<SETTING>` environment variables.
+    pub env: Vec<(String, String)>,
+    /// `--set key=value` flags, e.g. `clusters.development1.region=us-west-2`.
+    pub flags: Vec<String>,
+}
+
+impl Layers {
+    /// Layers from clusterctl's environment and command line.
+    pub fn new(profile: Option<&str>, flags: Vec<String>) -> Self {
+        Layers {
+            profile: profile.map(str::to_owned),
+            env: env::vars()
+                .filter(|(k, _)| k.starts_with(ENV_PREFIX) && k != "CLUSTERCTL_PROFILE")
+                .collect(),
+            flags,
+        }
+    }
+}
+
+const ENV_PREFIX: &str = "CLUSTERCTL_";
+
+/// Top level settings that can be set from the environment.
+const SETTINGS: [&str; 9] = [
+    "terraforming_path",
+    "kubernetes_deployments_path",
+    "keybase_secure_manifests_path",
+    "kubernetes_deployments_revision",
+    "kubernetes_deployments_ssh_key",
+    "infra_profile",
+    "v1_profile",
+    "assets_cache_path",
+    "region",
+];
+
 impl Config {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let s = std::fs::read_to_string(&path).context("config file not found")?;
-        toml::from_str(&s).context("config parsing error")
+    /// Read the config by merging, from lowest to highest precedence: built
+    /// in defaults, `path`, `config.d/*.toml` next to it in name order, the
+    /// selected profile, environment variables and flags. `sources` records
+    /// the layer every setting came from.
+    pub fn load<P: AsRef<Path>>(path: P, layers: &Layers) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let config_dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        let mut merged = Table::new();
+        let mut sources = BTreeMap::new();
+        let mut profiles = Table::new();
+
+        let mut defaults = Table::new();
+        defaults.insert("region".to_owned(), Value::String(default_region()));
+        defaults.insert(
+            "kubernetes_deployments_revision".to_owned(),
+            Value::String(default_revision()),
+        );
+        merge(&mut merged, defaults, "default", "", &mut sources);
+
+        let mut files = vec![];
+        if path.exists() {
+            files.push(path.to_path_buf());
+        }
+        let config_d = config_dir.join("config.d");
+        if config_d.is_dir() {
+            let mut extra = std::fs::read_dir(&config_d)?
+                .filter_map(|e| e.ok().map(|e| e.path()))
+                .filter(|p| p.extension().is_some_and(|ext| ext == "toml"))
+                .collect::<Vec<PathBuf>>();
+            extra.sort();
+            files.extend(extra);
+        }
+        let mut texts = BTreeMap::new();
+        for file in &files {
+            let (mut table, text) = read_layer(file, file == path)?;
+            let source = file.display().to_string();
+            texts.insert(source.clone(), text);
+            if let Some(Value::Table(named)) = table.remove("profiles") {
+                for (name, profile) in named {
+                    let profile = match profile {
+                        Value::Table(t) => t,
+                        _ => return Err(anyhow!("{}: profiles.{} is not a table", source, name)),
+                    };
+                    let entry = profiles
+                        .entry(name)
+                        .or_insert_with(|| Value::Table(Table::new()));
+                    if let Value::Table(t) = entry {
+                        merge(t, profile, "", "", &mut BTreeMap::new());
+                    }
+                }
+            }
+            merge(&mut merged, table, &source, "", &mut sources);
+        }
+
+        if let Some(name) = &layers.profile {
+            match profiles.remove(name) {
+                Some(Value::Table(profile)) => {
+                    let source = format!("profile {}", name);
+                    merge(&mut merged, profile, &source, "", &mut sources);
+                }
+                _ => {
+                    let known: Vec<&str> = profiles.keys().map(String::as_str).collect();
+                    return Err(anyhow!(
+                        "no config profile {}; the config files define: {}",
+                        name,
+                        if known.is_empty() {
+                            "none".to_owned()
+                        } else {
+                            known.join(", ")
+                        }
+                    ));
+                }
+            }
+        }
+
+        for (var, value) in &layers.env {
+            let key = var.trim_start_matches(ENV_PREFIX).to_lowercase();
+            // other tools may share the prefix
+            if !SETTINGS.contains(&key.as_str()) {
+                eprintln!(
+                    "Ignoring {}, which does not name a setting; settings are CLUSTERCTL_{}",
+                    var,
+                    SETTINGS.join(", CLUSTERCTL_").to_uppercase()
+                );
+                continue;
+            }
+            let mut table = Table::new();
+            table.insert(key, Value::String(value.clone()));
+            merge(
+                &mut merged,
+                table,
+                &format!("env {}", var),
+                "",
+                &mut sources,
+            );
+        }
+
+        for flag in &layers.flags {
+            let (key, value) = flag
+                .split_once('=')
+                .ok_or_else(|| anyhow!("--set {} is not key=value", flag))?;
+            let table = dotted(key, parse_value(value));
+            Value::Table(table.clone())
+                .try_into::<FileSchema>()
+                .with_context(|| format!("config error in --set {}", flag))?;
+            merge(&mut merged, table, "--set", "", &mut sources);
+        }
+
+        if files.is_empty() && layers.env.is_empty() && layers.flags.is_empty() {
+            return Err(anyhow!(
+                "config file {} not found; write one with `clusterctl config init`",
+                path.display()
+            ));
+        }
+        check_required(&merged, &texts, path)?;
+        let mut conf: Config = Value::Table(merged)
+            .try_into()
+            .context("config parsing error")?;
+        conf.config_dir = config_dir;
+        conf.sources = sources;
+        Ok(conf)
+    }
+
+    /// Every setting in effect, one `key = value` line each, with the layer it
+    /// came from.
+    pub fn show(&self) -> Result<String, Error> {
+        let value = Value::try_from(self)?;
+        let mut settings = vec![];
+        flatten("", value, &mut settings);
+        let width = settings
+            .iter()
+            .map(|(k, v)| k.len() + v.to_string().len())
+            .max();
+        let mut out = String::new();
+        for (key, value) in settings {
+            let line = format!("{} = {}", key, value);
+            let source = self.sources.get(&key).map(String::as_str);
+            out.push_str(&format!(
+                "{:<width$}  # {}\n",
+                line,
+                source.unwrap_or("default"),
+                width = width.unwrap_or(0) + 3
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Ids of every cluster in the inventory, in order.
+    pub fn cluster_ids(&self) -> Result<Vec<&str>, Error> {
+        if self.clusters.is_empty() {
+            return Err(anyhow!(
+                "no clusters in config.toml; declare each one in a [clusters.<id>] table"
+            ));
+        }
+        Ok(self.clusters.keys().map(String::as_str).collect())
+    }
+
+    pub fn cluster(&self, cluster_id: &str) -> Result<&Cluster, Error> {
+        let ids = self.cluster_ids()?;
+        self.clusters.get(cluster_id).ok_or_else(|| {
+            anyhow!(
+                "unknown cluster id {}; config.toml has {}",
+                cluster_id,
+                ids.join(", ")
+            )
+        })
+    }
+}
+
+type Table = toml::value::Table;
+
+fn default_revision() -> String {
+    "master".to_owned()
+}
+
+/// Settings without a default.
+const REQUIRED: [&str; 7] = [
+    "terraforming_path",
+    "kubernetes_deployments_path",
+    "keybase_secure_manifests_path",
+    "kubernetes_deployments_ssh_key",
+    "infra_profile",
+    "v1_profile",
+    "assets_cache_path",
+];
+
+/// Read one config file. config.toml is migrated to the current schema in
+/// memory; any other file must already be written for it. Returns the table
+/// and the text it was read from.
+fn read_layer(path: &Path, main: bool) -> Result<(Table, String), Error> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    let schema = validate(&text).with_context(|| format!("config error in {}", path.display()))?;
+    let text = if main {
+        let (migrated, from) =
+            migrate(&text).with_context(|| format!("config error in {}", path.display()))?;
+        if from < VERSION {
+            eprintln!(
+                "{} is written for config version {}; reading it as version {}. \
+                 Run `clusterctl config migrate` to update the file.",
+                path.display(),
+                from,
+                VERSION
+            );
+        }
+        migrated
+    } else {
+        match schema.version {
+            Some(version) if version != VERSION => {
+                return Err(anyhow!(
+                    "{} is written for config version {}, but only config.toml is migrated; \
+                     update it to version {} by hand",
+                    path.display(),
+                    version,
+                    VERSION
+                ))
+            }
+            _ => text,
+        }
+    };
+    let table = toml::from_str(&text)
+        .with_context(|| format!("config parsing error in {}", path.display()))?;
+    Ok((table, text))
+}
+
+/// One config file or profile as written, with every setting optional. Reading
+/// a file into it reports a misspelled or mistyped setting with its line.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSchema {
+    version: Option<u32>,
+    terraforming_path: Option<String>,
+    kubernetes_deployments_path: Option<String>,
+    keybase_secure_manifests_path: Option<String>,
+    kubernetes_deployments_revision: Option<String>,
+    kubernetes_deployments_ssh_key: Option<String>,
+    infra_profile: Option<String>,
+    v1_profile: Option<String>,
+    assets_cache_path: Option<String>,
+    region: Option<String>,
+    argocd_insecure: Option<bool>,
+    #[serde(default)]
+    clusters: BTreeMap<String, ClusterSchema>,
+    policy: Option<Policy>,
+    #[serde(default)]
+    profiles: BTreeMap<String, FileSchema>,
+}
+
+/// `Cluster` with every setting optional, since config.d files and profiles
+/// may set only some of them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterSchema {
+    environment: Option<String>,
+    default_namespace: Option<String>,
+    region: Option<String>,
+    aws_profile: Option<String>,
+    assets_bucket: Option<String>,
+    namespaces: Option<Vec<String>>,
+    terraform_workspace: Option<String>,
+}
+
+fn validate(text: &str) -> Result<FileSchema, Error> {
+    let schema: FileSchema = toml::from_str(text).map_err(|e| locate(text, e))?;
+    for (name, profile) in &schema.profiles {
+        if profile.version.is_some() || !profile.profiles.is_empty() {
+            return Err(anyhow!(
+                "profiles.{} may only hold settings and clusters",
+                name
+            ));
+        }
+    }
+    Ok(schema)
+}
+
+/// toml places an unknown field at the start of its table; point at the line
+/// the field is on instead.
+fn locate(text: &str, err: toml::de::Error) -> Error {
+    let msg = err.to_string();
+    let field = msg
+        .strip_prefix("unknown field `")
+        .and_then(|rest| rest.split('`').next());
+    let line = field.and_then(|field| {
+        text.lines()
+            .position(|l| l.split('=').next().map(str::trim) == Some(field))
+    });
+    match line {
+        Some(line) => {
+            let msg = msg.split(" at line ").next().unwrap_or(&msg);
+            anyhow!("{} at line {}", msg, line + 1)
+        }
+        None => err.into(),
+    }
+}
+
+/// Fail with the name of the first setting nobody set, and for a cluster,
+/// where it was declared.
+fn check_required(
+    merged: &Table,
+    texts: &BTreeMap<String, String>,
+    path: &Path,
+) -> Result<(), Error> {
+    for key in REQUIRED.iter() {
+        if !merged.contains_key(*key) {
+            return Err(anyhow!(
+                "missing setting {}: add it to {} or set {}{}",
+                key,
+                path.display(),
+                ENV_PREFIX,
+                key.to_uppercase()
+            ));
+        }
+    }
+    if let Some(Value::Table(clusters)) = merged.get("clusters") {
+        for (id, cluster) in clusters {
+            if cluster.get("environment").is_none() {
+                let header = format!("[clusters.{}]", id);
+                let place = texts
+                    .iter()
+                    .find_map(|(file, text)| {
+                        let line = text.lines().position(|l| l.trim() == header)?;
+                        Some(format!(" at line {} of {}", line + 1, file))
+                    })
+                    .unwrap_or_default();
+                return Err(anyhow!(
+                    "missing setting clusters.{}.environment: {}{} has no environment",
+                    id,
+                    header,
+                    place
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Clusters that clusterctl knew about before they were declared in config.
+const VERSION_1_CLUSTERS: [(&str, &str); 6] = [
+    ("development0", "development"),
+    ("development1", "development"),
+    ("development2", "development"),
+    ("production0", "production"),
+    ("production1", "production"),
+    ("production2", "production"),
+];
+
+/// Bring the text of a config.toml written for an older schema up to
+/// `VERSION`, keeping its comments. Returns the new text and the version the
+/// file was written for.
+pub fn migrate(text: &str) -> Result<(String, u32), Error> {
+    let table: Table = toml::from_str(text)?;
+    let from = match table.get("version") {
+        None => 1,
+        Some(Value::Integer(v)) if *v >= 1 => *v as u32,
+        Some(v) => return Err(anyhow!("version must be a number from 1 up, not {}", v)),
+    };
+    if from > VERSION {
+        return Err(anyhow!(
+            "config version {} is newer than this clusterctl, which reads up to version {}; \
+             upgrade clusterctl",
+            from,
+            VERSION
+        ));
+    }
+    if from == VERSION {
+        return Ok((text.to_owned(), from));
+    }
+
+    let mut text = text.to_owned();
+    // 1 -> 2: clusters are declared in config
+    if from < 2 && !table.contains_key("clusters") {
+        text.push_str("\n# The clusters clusterctl used to know about\n");
+        for (id, environment) in VERSION_1_CLUSTERS.iter() {
+            text.push_str(&format!(
+                "\n[clusters.{}]\nenvironment = \"{}\"\nnamespaces = [\"mars\"]\n",
+                id, environment
+            ));
+        }
+    }
+
+    // replace the version line, which comes before any table
+    let version_line = format!("version = {}", VERSION);
+    let mut lines: Vec<String> = text.lines().map(str::to_owned).collect();
+    let top = lines
+        .iter()
+        .position(|l| l.trim_start().starts_with('['))
+        .unwrap_or(lines.len());
+    match lines[..top]
+        .iter()
+        .position(|l| l.split('=').next().map(str::trim) == Some("version"))
+    {
+        Some(i) => lines[i] = version_line,
+        None => lines.insert(0, format!("{}\n", version_line)),
+    }
+    Ok((lines.join("\n") + "\n", from))
+}
+
+/// Rewrite the config.toml at `path` for the current schema. The original is
+/// kept next to it as `config.toml.v<version>.bak`.
+pub fn migrate_file(path: &Path) -> Result<(), Error> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read config file {}", path.display()))?;
+    validate(&text).with_context(|| format!("config error in {}", path.display()))?;
+    let (migrated, from) =
+        migrate(&text).with_context(|| format!("config error in {}", path.display()))?;
+    if from == VERSION {
+        println!("{} is already version {}", path.display(), VERSION);
+        return Ok(());
+    }
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(format!(".v{}.bak", from));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)
+        .with_context(|| format!("could not back up {}", path.display()))?;
+    std::fs::write(path, migrated)?;
+    println!(
+        "Migrated {} from version {} to {}; the original is at {}",
+        path.display(),
+        from,
+        VERSION,
+        backup.display()
+    );
+    Ok(())
+}
+
+/// Merge `layer` into `base`. Tables are merged key by key; anything else
+/// replaces what was there. The dotted key of every value taken from `layer`
+/// is recorded in `sources`.
+fn merge(
+    base: &mut Table,
+    layer: Table,
+    source: &str,
+    prefix: &str,
+    sources: &mut BTreeMap<String, String>,
+) {
+    for (key, value) in layer {
+        let dotted = format!("{}{}", prefix, key);
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(table)) => {
+                merge(existing, table, source, &format!("{}.", dotted), sources);
+            }
+            (_, value) => {
+                let mut leaves = vec![];
+                flatten(&dotted, value.clone(), &mut leaves);
+                for (leaf, _) in leaves {
+                    sources.insert(leaf, source.to_owned());
+                }
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The non-table values under `value`, by dotted key.
+fn flatten(key: &str, value: Value, out: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Table(table) => {
+            for (k, v) in table {
+                let dotted = if key.is_empty() {
+                    k
+                } else {
+                    format!("{}.{}", key, k)
+                };
+                flatten(&dotted, v, out);
+            }
+        }
+        value => out.push((key.to_owned(), value)),
+    }
+}
+
+/// A table holding `value` at the dotted `key`.
+fn dotted(key: &str, value: Value) -> Table {
+    let mut parts = key.rsplit('.');
+    let mut table = Table::new();
+    table.insert(parts.next().unwrap_or_default().to_owned(), value);
+    for part in parts {
+        let mut outer = Table::new();
+        outer.insert(part.to_owned(), Value::Table(table));
+        table = outer;
     }
+    table
+}
+
+/// A flag's value as TOML, e.g. `["mars"]`, or else as a plain string.
+fn parse_value(s: &str) -> Value {
+    toml::from_str::<Table>(&format!("v = {}", s))
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(s.to_owned()))
+}
+
+/// The settings every config.toml must have, as tests write them.
+#[cfg(test)]
+pub const TEST_SETTINGS: &str = r#"terraforming_path = "/terraforming"
+kubernetes_deployments_path = "/kubernetes-deployments"
+kubernetes_deployments_ssh_key = "/key"
+keybase_secure_manifests_path = "/secure"
+infra_profile = "infra"
+v1_profile = "v1"
+assets_cache_path = "/assets"
+"#;
+
+/// `TEST_SETTINGS` and a development1 cluster, followed by `tables`.
+#[cfg(test)]
+pub fn test_config_toml(tables: &str) -> String {
+    format!(
+        "{}\n[clusters.development1]\nenvironment = \"development\"\nnamespaces = [\"mars\"]\n{}",
+        TEST_SETTINGS, tables
+    )
+}
+
+/// The config most tests run against; see `test_config_toml`.
+#[cfg(test)]
+pub fn test_config() -> Config {
+    toml::from_str(&test_config_toml("")).unwrap()
+}
+
+#[test]
+fn test_layers() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-layers-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("config.d")).unwrap();
+    std::fs::write(
+        dir.join("config.toml"),
+        test_config_toml("\n[profiles.ci]\nassets_cache_path = \"/ci/assets\"\n"),
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("config.d/10-me.toml"),
+        r#"
+infra_profile = "infra_me"
+
+[clusters.development1]
+namespaces = ["mars", "venus"]
+"#,
+    )
+    .unwrap();
+
+    let layers = Layers {
+        profile: Some("ci".to_owned()),
+        env: vec![(
+            "CLUSTERCTL_TERRAFORMING_PATH".to_owned(),
+            "/ci/terraforming".to_owned(),
+        )],
+        flags: vec!["clusters.development1.region=us-west-2".to_owned()],
+    };
+    let conf = Config::load(dir.join("config.toml"), &layers).unwrap();
+    assert_eq!(conf.terraforming_path, "/ci/terraforming");
+    assert_eq!(conf.infra_profile, "infra_me");
+    assert_eq!(conf.assets_cache_path, "/ci/assets");
+    assert_eq!(conf.kubernetes_deployments_revision, "master");
+    let cluster = &conf.clusters["development1"];
+    assert_eq!(cluster.environment, "development");
+    assert_eq!(cluster.namespaces, vec!["mars", "venus"]);
+    assert_eq!(cluster.region.as_deref(), Some("us-west-2"));
+
+    let shown = conf.show().unwrap();
+    let source = |key: &str| {
+        let line = shown
+            .lines()
+            .find(|l| l.starts_with(&format!("{} =", key)))
+            .unwrap();
+        line.rsplit("# ").next().unwrap().to_owned()
+    };
+    assert_eq!(
+        source("terraforming_path"),
+        "env CLUSTERCTL_TERRAFORMING_PATH"
+    );
+    assert_eq!(
+        source("infra_profile"),
+        dir.join("config.d/10-me.toml").display().to_string()
+    );
+    assert_eq!(source("assets_cache_path"), "profile ci");
+    assert_eq!(source("region"), "default");
+    assert_eq!(source("clusters.development1.region"), "--set");
+    assert_eq!(
+        source("v1_profile"),
+        dir.join("config.toml").display().to_string()
+    );
+
+    let err = Config::load(
+        dir.join("config.toml"),
+        &Layers {
+            profile: Some("laptop".to_owned()),
+            ..Layers::default()
+        },
+    )
+    .unwrap_err();
+    assert!(err.to_string().contains("define: ci"));
+
+    // a misspelled flag is an error, an unrelated variable is not
+    let load = |env: &str, flag: &str| {
+        let layers = Layers {
+            profile: None,
+            env: vec![(env.to_owned(), "x".to_owned())],
+            flags: vec![flag.to_owned()],
+        };
+        Config::load(dir.join("config.toml"), &layers)
+    };
+    let err = load("CLUSTERCTL_REGION", "asets_cache_path=/x").unwrap_err();
+    let err = format!("{:#}", err);
+    assert!(
+        err.starts_with(
+            "config error in --set asets_cache_path=/x: unknown field `asets_cache_path`"
+        ),
+        "{}",
+        err
+    );
+    let err = load("CLUSTERCTL_REGION", "clusters.development1.regoin=eu").unwrap_err();
+    assert!(format!("{:#}", err).contains("unknown field `regoin`"));
+    let conf = load("CLUSTERCTL_TOKEN", "assets_cache_path=/x").unwrap();
+    assert_eq!(conf.assets_cache_path, "/x");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_migrate() {
+    let v1 = format!("# paths for my laptop\n{}", TEST_SETTINGS);
+    let (v2, from) = migrate(&v1).unwrap();
+    assert_eq!(from, 1);
+    assert!(v2.starts_with("version = 2\n\n# paths for my laptop\n"));
+    let conf: Config = toml::from_str(&v2).unwrap();
+    assert_eq!(conf.version, 2);
+    assert_eq!(conf.cluster_ids().unwrap().len(), 6);
+    assert_eq!(conf.clusters["production1"].environment, "production");
+    assert_eq!(conf.clusters["production1"].namespaces, vec!["mars"]);
+    // already current
+    assert_eq!(migrate(&v2).unwrap(), (v2.clone(), 2));
+
+    let err = migrate("version = 3\n").unwrap_err();
+    assert!(err.to_string().contains("upgrade clusterctl"));
+
+    let dir = std::env::temp_dir().join(format!("clusterctl-migrate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(&path, &v1).unwrap();
+    migrate_file(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), v2);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("config.toml.v1.bak")).unwrap(),
+        v1
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config_errors() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-errors-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    let load = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        let err = Config::load(&path, &Layers::default()).unwrap_err();
+        format!("{:#}", err)
+    };
+    let settings = format!("version = 2\n{}", TEST_SETTINGS);
+
+    let err = load(&format!("{}regoin = \"eu\"\n", settings));
+    assert!(err.contains("unknown field `regoin`"), "{}", err);
+    assert!(err.contains("line 9"), "{}", err);
+
+    let err = load(&settings.replace("assets_cache_path = \"/assets\"\n", ""));
+    assert!(err.contains("missing setting assets_cache_path"), "{}", err);
+
+    let err = load(&format!(
+        "{}\n[clusters.development1]\nnamespaces = []\n",
+        settings
+    ));
+    assert!(err.contains("clusters.development1.environment"), "{}", err);
+    assert!(err.contains("at line 10 of"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
 }
@@ -0,0 +1,175 @@
+use crate::config::Config;
+use crate::runner::Cmd;
+use crate::workflow::Vars;
+use anyhow::Error;
+use std::path::PathBuf;
+
+/// Everything a command needs to know about the cluster it operates on. Every
+/// command gets its environment from a context rather than from clusterctl's
+/// own, so one process can work on more than one cluster.
+#[derive(Debug, Clone)]
+pub struct ClusterContext {
+    pub cluster_id: String,
+    pub environment: String,
+    pub default_namespace: String,
+    /// Namespaces created next to the default namespace.
+    pub namespaces: Vec<String>,
+    /// Set once the cluster's kubeconfig has been downloaded.
+    pub kubeconfig: Option<PathBuf>,
+    pub infra_profile: String,
+    pub v1_profile: String,
+    pub region: String,
+    /// Looked up by name when not set in config.toml.
+    pub assets_bucket: Option<String>,
+    pub terraform_workspace: String,
+}
+
+impl ClusterContext {
+    /// The context of `cluster_id` as declared in config.toml.
+    pub fn new(conf: &Config, cluster_id: &str) -> Result<Self, Error> {
+        let cluster = conf.cluster(cluster_id)?;
+        Ok(ClusterContext {
+            cluster_id: cluster_id.to_owned(),
+            environment: cluster.environment.clone(),
+            default_namespace: cluster
+                .default_namespace
+                .clone()
+                .unwrap_or_else(|| cluster.environment.clone()),
+            namespaces: cluster.namespaces.clone(),
+            kubeconfig: None,
+            infra_profile: cluster
+                .aws_profile
+                .clone()
+                .unwrap_or_else(|| conf.infra_profile.clone()),
+            v1_profile: conf.v1_profile.clone(),
+            region: cluster
+                .region
+                .clone()
+                .unwrap_or_else(|| conf.region.clone()),
+            assets_bucket: cluster.assets_bucket.clone(),
+            terraform_workspace: cluster
+                .terraform_workspace
+                .clone()
+                .unwrap_or_else(|| cluster_id.to_owned()),
+        })
+    }
+
+    /// Variables that workflow commands may refer to. These take precedence
+    /// over config.toml settings of the same name.
+    pub fn vars(&self) -> Vars {
+        let mut vars = Vars::new();
+        let settings = [
+            ("cluster_id", &self.cluster_id),
+            ("environment", &self.environment),
+            ("default_namespace", &self.default_namespace),
+            ("infra_profile", &self.infra_profile),
+            ("v1_profile", &self.v1_profile),
+            ("region", &self.region),
+            ("terraform_workspace", &self.terraform_workspace),
+        ];
+        for (k, v) in settings.iter() {
+            vars.insert(k.to_string(), v.to_string());
+        }
+        vars
+    }
+
+    /// Give `cmd` this cluster's environment: its AWS region and, once known,
+    /// its kubeconfig. Variables the command sets itself are left alone.
+    pub fn apply(&self, cmd: &mut Cmd) {
+        let mut env = vec![("AWS_DEFAULT_REGION", self.region.clone())];
+        if let Some(path) = &self.kubeconfig {
+            env.push(("KUBECONFIG", path.to_string_lossy().into_owned()));
+        }
+        for (k, v) in env {
+            let set = cmd.env.as_ref().is_some_and(|e| e.borrow().contains_key(k));
+            if !set {
+                cmd.env(k, &v);
+            }
+        }
+    }
+
+    /// An aws cli command run with the infra profile.
+    pub fn aws(&self, args: Vec<&str>) -> Cmd {
+        let mut command = vec!["aws"];
+        command.extend(args);
+        let mut cmd = Cmd::new(command);
+        cmd.env("AWS_PROFILE", &self.infra_profile);
+        self.apply(&mut cmd);
+        cmd
+    }
+}
+
+/// development1 from `config::test_config`.
+#[cfg(test)]
+pub fn test_context() -> ClusterContext {
+    ClusterContext::new(&crate::config::test_config(), "development1").unwrap()
+}
+
+#[test]
+fn test_context_env() {
+    let mut ctx = test_context();
+    let other = ClusterContext {
+        cluster_id: "production1".to_owned(),
+        kubeconfig: Some(PathBuf::from("/assets/production1/kubeconfig")),
+        ..ctx.clone()
+    };
+    ctx.kubeconfig = Some(PathBuf::from("/assets/development1/kubeconfig"));
+
+    let mut a = Cmd::new(vec!["kubectl", "get", "pods"]);
+    ctx.apply(&mut a);
+    let mut b = Cmd::new(vec!["kubectl", "get", "pods"]);
+    other.apply(&mut b);
+    assert_eq!(
+        a.env.unwrap().borrow()["KUBECONFIG"],
+        "/assets/development1/kubeconfig"
+    );
+    assert_eq!(
+        b.env.unwrap().borrow()["KUBECONFIG"],
+        "/assets/production1/kubeconfig"
+    );
+
+    // a command's own setting wins
+    let mut c = Cmd::new(vec!["aws", "s3", "ls"]);
+    c.env("AWS_DEFAULT_REGION", "eu-west-1");
+    ctx.apply(&mut c);
+    assert_eq!(c.env.unwrap().borrow()["AWS_DEFAULT_REGION"], "eu-west-1");
+}
+
+#[test]
+fn test_context_from_inventory() {
+    let conf: Config = toml::from_str(&crate::config::test_config_toml(
+        r#"
+[clusters.staging-eu]
+environment = "production"
+default_namespace = "staging"
+region = "eu-west-1"
+aws_profile = "eu_power_user"
+assets_bucket = "staging-eu-assets"
+terraform_workspace = "eu1"
+"#,
+    ))
+    .unwrap();
+    assert_eq!(
+        conf.cluster_ids().unwrap(),
+        vec!["development1", "staging-eu"]
+    );
+
+    let ctx = ClusterContext::new(&conf, "development1").unwrap();
+    assert_eq!(ctx.default_namespace, "development");
+    assert_eq!(ctx.namespaces, vec!["mars"]);
+    assert_eq!(ctx.infra_profile, "infra");
+    assert_eq!(ctx.region, "us-east-1");
+    assert_eq!(ctx.terraform_workspace, "development1");
+
+    let ctx = ClusterContext::new(&conf, "staging-eu").unwrap();
+    let vars = ctx.vars();
+    assert_eq!(vars["default_namespace"], "staging");
+    assert_eq!(vars["infra_profile"], "eu_power_user");
+    assert_eq!(vars["v1_profile"], "v1");
+    assert_eq!(vars["region"], "eu-west-1");
+    assert_eq!(vars["terraform_workspace"], "eu1");
+    assert_eq!(ctx.assets_bucket.as_deref(), Some("staging-eu-assets"));
+
+    let err = ClusterContext::new(&conf, "production9").unwrap_err();
+    assert!(err.to_string().contains("development1, staging-eu"));
+}
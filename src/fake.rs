@@ -0,0 +1,112 @@
+use crate::runner::{Cmd, CommandExecutor, Outcome};
+use crate::session::Session;
+use anyhow::Error;
+use std::cell::RefCell;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::rc::Rc;
+
+/// Records every command instead of running it. A command exits 0 with no
+/// output, unless a scripted response matches its command line. Like most
+/// tools, a command that fails prints its output on stderr.
+#[derive(Default)]
+pub struct FakeExecutor {
+    /// Pattern, exit code, output, and whether the response is used once.
+    responses: RefCell<Vec<(String, i32, String, bool)>>,
+    calls: RefCell<Vec<Cmd>>,
+}
+
+impl FakeExecutor {
+    pub fn new() -> Self {
+        FakeExecutor::default()
+    }
+
+    /// Commands whose command line contains `pattern` exit with `code` and
+    /// print `output`. Later responses take precedence.
+    pub fn respond(&self, pattern: &str, code: i32, output: &str) -> &Self {
+        self.responses
+            .borrow_mut()
+            .push((pattern.to_owned(), code, output.to_owned(), false));
+        self
+    }
+
+    /// Like `respond`, but only for the next matching command, e.g. a plan
+    /// that has nothing left to do once it has been applied.
+    pub fn respond_once(&self, pattern: &str, code: i32, output: &str) -> &Self {
+        self.responses
+            .borrow_mut()
+            .push((pattern.to_owned(), code, output.to_owned(), true));
+        self
+    }
+
+    /// Every command executed so far, in order.
+    pub fn calls(&self) -> Vec<Cmd> {
+        self.calls.borrow().clone()
+    }
+
+    /// The command line of every command executed so far, in order.
+    pub fn command_lines(&self) -> Vec<String> {
+        self.calls.borrow().iter().map(Cmd::command_line).collect()
+    }
+
+    fn respond_to(&self, cmd: &Cmd) -> (ExitStatus, String) {
+        self.calls.borrow_mut().push(cmd.clone());
+        let line = cmd.command.join(" ");
+        let mut responses = self.responses.borrow_mut();
+        let found = responses
+            .iter()
+            .rposition(|(pattern, _, _, _)| line.contains(pattern.as_str()));
+        let (code, output) = match found {
+            Some(i) if responses[i].3 => {
+                let (_, code, output, _) = responses.remove(i);
+                (code, output)
+            }
+            Some(i) => (responses[i].1, responses[i].2.clone()),
+            None => (0, String::new()),
+        };
+        (ExitStatus::from_raw(code << 8), output)
+    }
+}
+
+impl CommandExecutor for FakeExecutor {
+    fn run(&self, cmd: &Cmd, capture: bool) -> Result<Outcome, Error> {
+        let (status, output) = self.respond_to(cmd);
+        if let Some(path) = &cmd.writes_file {
+            std::fs::write(path, &output)?;
+        }
+        let output = if capture { output } else { String::new() };
+        let stderr = if status.success() {
+            String::new()
+        } else {
+            output.clone()
+        };
+        Ok(Outcome {
+            status,
+            output,
+            stderr,
+            timed_out: false,
+        })
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<Output, Error> {
+        let (status, output) = self.respond_to(cmd);
+        let (stdout, stderr) = if status.success() {
+            (output, String::new())
+        } else {
+            (String::new(), output)
+        };
+        Ok(Output {
+            status,
+            stdout: stdout.into_bytes(),
+            stderr: stderr.into_bytes(),
+        })
+    }
+}
+
+/// A session that answers yes to every prompt and runs its commands with
+/// `fake`.
+pub fn fake_session(fake: &Rc<FakeExecutor>) -> Session {
+    Session::new(true, None, false, false)
+        .unwrap()
+        .with_executor(fake.clone())
+}
@@ -0,0 +1,104 @@
+use crate::runner::Cmd;
+use crate::session::{Choice, Session};
+use anyhow::{anyhow, Context, Error};
+use std::path::Path;
+
+fn git<P: AsRef<Path>>(dir: P, args: Vec<&str>) -> Cmd {
+    let mut command = vec!["git"];
+    command.extend(args);
+    let mut cmd = Cmd::new(command);
+    cmd.dir(dir.as_ref().to_path_buf());
+    cmd
+}
+
+/// Lists uncommitted changes and untracked files, one per line.
+pub fn status<P: AsRef<Path>>(dir: P) -> Cmd {
+    git(dir, vec!["status", "--porcelain"])
+}
+
+/// Prints the commit `rev` points at, or fails if there is none.
+pub fn rev_parse<P: AsRef<Path>>(dir: P, rev: &str) -> Cmd {
+    let spec = format!("{}^{{commit}}", rev);
+    git(dir, vec!["rev-parse", "--verify", &spec])
+}
+
+pub fn checkout<P: AsRef<Path>>(dir: P, rev: &str) -> Cmd {
+    git(dir, vec!["checkout", "--quiet", rev])
+}
+
+/// Make sure the checkout at `dir` is clean and at `revision`, offering to
+/// check it out if it is not. Anything templated from the checkout is then
+/// what `revision` says it is.
+pub fn pin(session: &Session, dir: &str, revision: &str) -> Result<(), Error> {
+    let changes = session.query(&status(dir), "")?;
+    if !changes.is_empty() {
+        return Err(anyhow!(
+            "{} has uncommitted changes; commit or stash them first:\n{}",
+            dir,
+            changes
+        ));
+    }
+    let head = session.query(&rev_parse(dir, "HEAD"), "<commit>")?;
+    let wanted = session
+        .query(&rev_parse(dir, revision), "<commit>")
+        .with_context(|| format!("{} has no revision {}; fetch it first", dir, revision))?;
+    if head == wanted {
+        println!("\n{} is at {} ({})", dir, revision, wanted);
+        return Ok(());
+    }
+
+    let cmd = checkout(dir, revision);
+    let prompt = format!("{} is not at {}. Check it out?", dir, revision);
+    println!("---\n{}", cmd.describe());
+    match session.choose(&prompt, &cmd)? {
+        Choice::Execute => {
+            if let Some(outcome) = session.run(&prompt, &cmd, false)? {
+                if !outcome.status.success() {
+                    return Err(anyhow!("could not check out {} in {}", revision, dir));
+                }
+            }
+            Ok(())
+        }
+        Choice::Skip | Choice::Exit => Err(anyhow!(
+            "{} must be at {} before anything is deployed from it",
+            dir,
+            revision
+        )),
+    }
+}
+
+#[test]
+fn test_pin() {
+    use crate::fake::{fake_session, FakeExecutor};
+    use std::rc::Rc;
+
+    // already there
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("rev-parse", 0, "4f2a9c1");
+    pin(&fake_session(&fake), "/deployments", "release-42").unwrap();
+    assert_eq!(
+        fake.command_lines(),
+        vec![
+            "git status --porcelain",
+            "git rev-parse --verify HEAD^{commit}",
+            "git rev-parse --verify release-42^{commit}",
+        ]
+    );
+
+    // somewhere else
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("rev-parse", 0, "4f2a9c1");
+    fake.respond("release-42", 0, "77d0e3b");
+    pin(&fake_session(&fake), "/deployments", "release-42").unwrap();
+    assert_eq!(
+        fake.command_lines().last().unwrap(),
+        "git checkout --quiet release-42"
+    );
+
+    // a dirty tree is never touched
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("status", 0, " M charts/pp-argo-cd/values.yaml");
+    let err = pin(&fake_session(&fake), "/deployments", "release-42").unwrap_err();
+    assert!(err.to_string().contains("uncommitted changes"));
+    assert_eq!(fake.command_lines().len(), 1);
+}
@@ -0,0 +1,224 @@
+use crate::runner::Cmd;
+use crate::session::Choice;
+use anyhow::{anyhow, Context, Error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// One step of a workflow, as it was run (or not run) by the operator.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub prompt: String,
+    /// The arguments, with sensitive values masked.
+    pub argv: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    /// Names of the extra environment variables. Values are never recorded.
+    pub env_keys: Vec<String>,
+    pub choice: Choice,
+    pub exit_code: Option<i32>,
+    /// Set if the command could not be started at all.
+    pub error: Option<String>,
+    /// Policy violations the operator overrode to run the command.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub policy_overrides: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub duration_ms: i64,
+}
+
+impl Entry {
+    pub fn new(prompt: &str, cmd: &Cmd, choice: Choice, started_at: DateTime<Utc>) -> Self {
+        let mut env_keys: Vec<String> = match &cmd.env {
+            Some(e) => e.borrow().keys().cloned().collect(),
+            None => vec![],
+        };
+        env_keys.sort();
+        let finished_at = Utc::now();
+        Entry {
+            prompt: prompt.to_owned(),
+            argv: cmd.argv(),
+            cwd: cmd.working_dir.clone(),
+            env_keys,
+            choice,
+            exit_code: None,
+            error: None,
+            policy_overrides: vec![],
+            started_at,
+            finished_at,
+            duration_ms: (finished_at - started_at).num_milliseconds(),
+        }
+    }
+}
+
+/// An append-only JSON-lines log of one workflow execution, stored at
+/// `assets_cache_path/<cluster_id>/runs/<run id>.jsonl`. The run id is the
+/// start time to the microsecond, clusterctl's pid and the workflow, so runs
+/// started together never share a journal.
+pub struct Journal {
+    pub id: String,
+    pub path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    pub fn create<P: AsRef<Path>>(
+        assets_cache_path: P,
+        cluster_id: &str,
+        workflow: &str,
+    ) -> Result<Self, Error> {
+        let dir = runs_dir(assets_cache_path, cluster_id);
+        std::fs::create_dir_all(&dir)?;
+        let id = format!(
+            "{}-{}-{}",
+            Utc::now().format("%Y%m%dT%H%M%S%.6fZ"),
+            std::process::id(),
+            workflow
+        );
+        Self::open(&dir, id)
+    }
+
+    /// Start a new journal; one that already exists is never appended to.
+    fn open(dir: &Path, id: String) -> Result<Self, Error> {
+        let path = dir.join(format!("{}.jsonl", id));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("could not create run journal {:?}", path))?;
+        Ok(Journal { id, path, file })
+    }
+
+    pub fn record(&mut self, entry: &Entry) -> Result<(), Error> {
+        let line = serde_json::to_string(entry)?;
+        writeln!(self.file, "{}", line)?;
+        Ok(())
+    }
+}
+
+fn runs_dir<P: AsRef<Path>>(assets_cache_path: P, cluster_id: &str) -> PathBuf {
+    assets_cache_path.as_ref().join(cluster_id).join("runs")
+}
+
+/// Find a journal by run id in any cluster's runs directory.
+pub fn find<P: AsRef<Path>>(assets_cache_path: P, id: &str) -> Result<PathBuf, Error> {
+    let file_name = format!("{}.jsonl", id);
+    for cluster in std::fs::read_dir(assets_cache_path.as_ref())? {
+        let path = cluster?.path().join("runs").join(&file_name);
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    Err(anyhow!("no run journal with id {}", id))
+}
+
+/// List the run ids recorded for every cluster, oldest first.
+pub fn list<P: AsRef<Path>>(assets_cache_path: P) -> Result<Vec<(String, String)>, Error> {
+    let mut runs = vec![];
+    if !assets_cache_path.as_ref().exists() {
+        return Ok(runs);
+    }
+    for cluster in std::fs::read_dir(assets_cache_path.as_ref())? {
+        let cluster = cluster?;
+        let dir = cluster.path().join("runs");
+        if !dir.is_dir() {
+            continue;
+        }
+        let cluster_id = cluster.file_name().to_string_lossy().into_owned();
+        for run in std::fs::read_dir(dir)? {
+            let path = run?.path();
+            if path.extension().is_some_and(|ext| ext == "jsonl") {
+                let id = path.file_stem().unwrap().to_string_lossy().into_owned();
+                runs.push((cluster_id.clone(), id));
+            }
+        }
+    }
+    runs.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(runs)
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>, Error> {
+    let f = File::open(&path)?;
+    let mut entries = vec![];
+    for (n, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .with_context(|| format!("malformed journal entry on line {}", n + 1))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Render a journal as a table, one step per line.
+pub fn render(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let result = match (&entry.choice, entry.exit_code, &entry.error) {
+            (Choice::Execute, _, Some(err)) => format!("error: {}", err),
+            (Choice::Execute, Some(code), None) => format!("exit {}", code),
+            (Choice::Execute, None, None) => "killed".to_owned(),
+            (Choice::Skip, _, _) => "skipped".to_owned(),
+            (Choice::Exit, _, _) => "exited".to_owned(),
+        };
+        out.push_str(&format!(
+            "{}  {:>8.1}s  {:<10}  {}\n",
+            entry.started_at.format("%Y-%m-%d %H:%M:%S"),
+            entry.duration_ms as f64 / 1000.0,
+            result,
+            entry.prompt,
+        ));
+        out.push_str(&format!("    {}\n", entry.argv.join(" ")));
+        if let Some(cwd) = &entry.cwd {
+            out.push_str(&format!("    in {}\n", cwd.display()));
+        }
+        if !entry.env_keys.is_empty() {
+            out.push_str(&format!("    env {}\n", entry.env_keys.join(", ")));
+        }
+        for violation in &entry.policy_overrides {
+            out.push_str(&format!("    overrode policy: {}\n", violation));
+        }
+    }
+    out
+}
+
+#[test]
+fn test_journal_roundtrip() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-journal-{}", std::process::id()));
+    let mut journal = Journal::create(&dir, "development1", "launch-cluster").unwrap();
+
+    let mut cmd = Cmd::new(vec!["terraform", "apply", "tfplan.out"]);
+    cmd.env("AWS_PROFILE", "infra_power_user");
+    let mut entry = Entry::new("Execute command?", &cmd, Choice::Execute, Utc::now());
+    entry.exit_code = Some(1);
+    journal.record(&entry).unwrap();
+    journal
+        .record(&Entry::new("Execute?", &cmd, Choice::Skip, Utc::now()))
+        .unwrap();
+
+    let path = find(&dir, &journal.id).unwrap();
+    let entries = read(&path).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].env_keys, vec!["AWS_PROFILE".to_owned()]);
+    assert_eq!(entries[0].exit_code, Some(1));
+    assert_eq!(entries[1].choice, Choice::Skip);
+
+    let line = std::fs::read_to_string(&path).unwrap();
+    assert!(!line.contains("infra_power_user"));
+    assert_eq!(
+        list(&dir).unwrap(),
+        vec![("development1".to_owned(), journal.id.clone())]
+    );
+
+    // runs started back to back get journals of their own
+    let next = Journal::create(&dir, "development1", "launch-cluster").unwrap();
+    assert_ne!(next.id, journal.id);
+    assert_eq!(read(&next.path).unwrap().len(), 0);
+    assert!(Journal::open(&runs_dir(&dir, "development1"), journal.id.clone()).is_err());
+    assert_eq!(read(&path).unwrap().len(), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,7 +1,7 @@
 #![allow(dead_code)]
 #![allow(unused_imports)]
 #![allow(unused_variables)]
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
 use clap::{App, Arg, Shell, SubCommand};
 use console::Style;
 use dialoguer::{theme::ColorfulTheme, Confirmation, Editor, Input, Select};
@@ -15,20 +15,35 @@ use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
 use std::str::FromStr;
 
+mod check;
+mod checkpoint;
 mod config;
-mod heapster;
-mod helm;
-mod kubectl;
+mod context;
+#[cfg(test)]
+mod fake;
+mod git;
+mod journal;
+mod playbook;
+mod policy;
 mod runner;
+mod session;
 mod terraform;
+mod wait;
+mod wizard;
+mod workflow;
+mod workflows;
 
 use config::Config;
-use runner::{Cmd, Expect};
+use context::ClusterContext;
+use policy::Policy;
+use runner::Cmd;
+use session::{Answers, Session};
+use workflow::{Vars, Workflow};
 
 fn main() -> Result<(), Error> {
     let default_dir = home_with(".config/clusterctl");
     let default_config = home_with(".config/clusterctl/config.toml");
-    create_dir(&default_dir.clone()).expect("could not create default config dir");
+    create_dir(default_dir.clone()).expect("could not create default config dir");
 
     let mut app = App::new("clusterctl")
         .about("Interactive wrapper that stands up and tears down Kubernetes")
@@ -41,6 +56,49 @@ fn main() -> Result<(), Error> {
                 .takes_value(true)
                 .default_value(&default_config),
         )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("apply the [profiles.<name>] settings from the config files")
+                .env("CLUSTERCTL_PROFILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("set")
+                .long("set")
+                .help("override a config setting, e.g. --set assets_cache_path=/tmp/assets")
+                .value_name("KEY=VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .short("y")
+                .long("yes")
+                .help("answer \"execute\" to every prompt"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("print every command a workflow would run without running it"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help("pick a workflow up at the first step the previous attempt did not finish"),
+        )
+        .arg(
+            Arg::with_name("override-policy")
+                .long("override-policy")
+                .help("apply terraform plans that break policy; every override is journaled"),
+        )
+        .arg(
+            Arg::with_name("answers")
+                .long("answers")
+                .help("path to a TOML file with per-step answers and the cluster id")
+                .takes_value(true),
+        )
         .subcommands(vec![
             SubCommand::with_name("cache-assets").about("cache generated kube configs locally"),
             SubCommand::with_name("completions")
@@ -50,15 +108,55 @@ fn main() -> Result<(), Error> {
                         .possible_values(&["bash", "zsh"])
                         .required(true),
                 ),
-            SubCommand::with_name("destroy-cluster").about("destroy a k8s cluster"),
+            SubCommand::with_name("destroy-cluster")
+                .about("destroy a k8s cluster")
+                .args(&workflow_args()),
             SubCommand::with_name("destroy-kubernetes-ingress")
-                .about("destroy the ingress DNS records"),
+                .about("destroy the ingress DNS records")
+                .args(&workflow_args()),
             SubCommand::with_name("launch-cluster")
-                .about("launch a new k8s cluster with the terraform tectonic installer"),
+                .about("launch a new k8s cluster with the terraform tectonic installer")
+                .args(&workflow_args()),
             SubCommand::with_name("namespace-init")
-                .about("create namespaces with secrets and config maps"),
-            SubCommand::with_name("argo-init").about("install and configure argo on a cluster"),
+                .about("create namespaces with secrets and config maps")
+                .args(&workflow_args()),
+            SubCommand::with_name("argo-init")
+                .about("install and configure argo on a cluster")
+                .args(&workflow_args()),
             SubCommand::with_name("tool-check").about("check for required tools on PATH"),
+            SubCommand::with_name("config")
+                .about("inspect or write config.toml")
+                .subcommands(vec![
+                    SubCommand::with_name("check")
+                        .about("check the paths and aws profiles in config.toml on this machine"),
+                    SubCommand::with_name("show")
+                        .about("print every setting in effect and where it came from"),
+                    SubCommand::with_name("init").about("write config.toml by answering questions"),
+                    SubCommand::with_name("migrate").about(
+                        "rewrite config.toml for this version of clusterctl, keeping a backup",
+                    ),
+                ]),
+            SubCommand::with_name("playbooks")
+                .about("inspect the playbooks that define workflows")
+                .subcommands(vec![
+                    SubCommand::with_name("list")
+                        .about("list playbooks and where each is loaded from"),
+                    SubCommand::with_name("show")
+                        .about("print the playbook in effect for a workflow")
+                        .arg(
+                            Arg::with_name("name")
+                                .required(true)
+                                .possible_values(&playbook::names()),
+                        ),
+                ]),
+            SubCommand::with_name("runs")
+                .about("inspect the journals of previous workflow runs")
+                .subcommands(vec![
+                    SubCommand::with_name("list").about("list recorded runs"),
+                    SubCommand::with_name("show")
+                        .about("show every step of a recorded run")
+                        .arg(Arg::with_name("id").required(true)),
+                ]),
         ]);
 
     let (bash, zsh) = completions(&mut app);
@@ -68,26 +166,99 @@ fn main() -> Result<(), Error> {
     let config_path = matches
         .value_of("config")
         .ok_or(anyhow!("could not locate config"))?;
-    let config = Config::from_file(&config_path)?;
-
-    // Subcommands
-    match matches.subcommand() {
-        ("completions", Some(args)) => match args.value_of("shell").unwrap() {
+    if let ("config", Some(args)) = matches.subcommand() {
+        match args.subcommand() {
+            ("init", _) => return wizard::run(Path::new(config_path), Path::new(&home_with(""))),
+            ("migrate", _) => return config::migrate_file(Path::new(config_path)),
+            _ => {}
+        }
+    }
+    let flags = matches
+        .values_of("set")
+        .map(|v| v.map(str::to_owned).collect())
+        .unwrap_or_default();
+    let layers = config::Layers::new(matches.value_of("profile"), flags);
+    let config = Config::load(config_path, &layers)?;
+
+    if let ("completions", Some(args)) = matches.subcommand() {
+        match args.value_of("shell").unwrap() {
             "bash" => io::stdout().lock().write_all(&bash).unwrap(),
             "zsh" => io::stdout().lock().write_all(&bash).unwrap(),
             _ => unreachable!(),
-        },
-        ("destroy-cluster", _) => destroy_cluster(&config)?,
-        ("destroy-kubernetes-ingress", _) => destroy_kubernetes_ingress(&config, None)?,
-        ("launch-cluster", _) => launch_cluster(&config)?,
-        ("namespace-init", _) => namespace_init(&config, None)?,
-        ("argo-init", _) => argo_init(&config, None)?,
+        }
+        return Ok(());
+    }
+
+    if let ("runs", Some(args)) = matches.subcommand() {
+        return runs(&config, args);
+    }
+
+    if let ("playbooks", Some(args)) = matches.subcommand() {
+        return playbooks(&config, args);
+    }
+
+    if let ("config", Some(args)) = matches.subcommand() {
+        return config_subcommand(&config, args);
+    }
+
+    if let (name, Some(args)) = matches.subcommand() {
+        if args.is_present("list-steps") {
+            let workflow =
+                workflows::load(name, &config)?.ok_or(anyhow!("{} is not a workflow", name))?;
+            print!("{}", workflow.list());
+            return Ok(());
+        }
+    }
+
+    let answers = match matches.value_of("answers") {
+        Some(path) => Some(Answers::from_file(path)?),
+        None => None,
+    };
+    let session = Session::new(
+        matches.is_present("yes"),
+        answers,
+        matches.is_present("dry-run"),
+        matches.is_present("resume"),
+    )?
+    .with_policy_override(matches.is_present("override-policy"));
+
+    // Subcommands
+    let (subcommand, args) = matches.subcommand();
+    let cluster_id = args
+        .and_then(|a| a.value_of("cluster"))
+        .map(|id| id.to_owned());
+    let step = args.and_then(|a| a.value_of("step"));
+    match subcommand {
+        "destroy-cluster" => destroy_cluster(&config, &session, cluster_id, step)?,
+        "destroy-kubernetes-ingress" => {
+            destroy_kubernetes_ingress(&config, &session, cluster_id, step)?
+        }
+        "launch-cluster" => launch_cluster(&config, &session, cluster_id, step)?,
+        "namespace-init" => namespace_init(&config, &session, cluster_id, step)?,
+        "argo-init" => argo_init(&config, &session, cluster_id, step)?,
         _ => return Err(anyhow!("you must provide a subcommand")),
     }
 
     Ok(())
 }
 
+/// Arguments shared by every workflow subcommand.
+fn workflow_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("cluster")
+            .long("cluster")
+            .help("cluster id from config.toml to operate on, instead of picking one from a list")
+            .takes_value(true),
+        Arg::with_name("list-steps")
+            .long("list-steps")
+            .help("list the steps of this workflow and exit"),
+        Arg::with_name("step")
+            .long("step")
+            .help("run only the named step (see --list-steps)")
+            .takes_value(true),
+    ]
+}
+
 fn completions(app: &mut App) -> (Vec<u8>, Vec<u8>) {
     let mut bash = Vec::<u8>::new();
     let mut zsh = Vec::<u8>::new();
@@ -97,336 +268,174 @@ fn completions(app: &mut App) -> (Vec<u8>, Vec<u8>) {
     (bash, zsh)
 }
 
-fn argo_init(conf: &Config, cluster_id: Option<String>) -> Result<(), Error> {
-    let cluster_id = cluster_id.unwrap_or(pick_cluster_id_prompt()?);
-    let infra_profile = &conf.infra_profile;
-
-    // fetch kubeconfig
-    let bucket = assets_bucket_name(&cluster_id, infra_profile)?;
-    let cache_dir = Path::new(&conf.assets_cache_path).join(&cluster_id);
-    create_dir(&cache_dir)?;
-    let kubeconfig_path = cache_dir.join("kubeconfig");
-    let path = kubeconfig_path
-        .to_str()
-        .ok_or(anyhow!("malformed assets path"))?;
-    download_kubeconfig(&bucket, infra_profile, path)?;
-
-    env::remove_var("KUBECONFIG");
-    env::set_var("KUBECONFIG", &path);
-
-    // create namespace
-    let c = Cmd::new(vec!["kubectl", "create", "ns", "argocd"]);
-    prompt_run! { "Execute?", c, Expect::Success };
-
-    let path = Path::new(&conf.kubernetes_deployments_path).to_path_buf();
-
-    let mut c = Cmd::new(vec!["helm", "dep", "update", "charts/pp-argo-cd"]);
-    let c = c.dir(path.clone());
-    prompt_run! { "Execute?", c, Expect::Success };
-
-    // Template your ArgoCD YAML manifests
-    let d_ns = default_namespace(&cluster_id);
-    let chart = format!("charts/pp-argo-cd/values-{}.yaml", d_ns);
-    let mut c = Cmd::new(vec![
-        "helm",
-        "template",
-        "-n",
-        "argocd",
-        "-f",
-        &chart,
-        "charts/pp-argo-cd",
-    ]);
-    let c = c.dir(path.clone());
-    let outfile = PathBuf::new().join("/tmp/argo_template.yaml");
-    let c = c.writes_file(outfile);
-    prompt_run! { "Template pp-argo-cd chart? File will be written to /tmp/argo_template.yaml", c, Expect::Success };
-    println!("Note: the warning \"destination for dexConfig is a table\" can be ignored");
-
-    // Deploy ArgoCD
-    let c = Cmd::new(vec![
-        "kubectl",
-        "apply",
-        "-n",
-        "argocd",
-        "-f",
-        "/tmp/argo_template.yaml",
-    ]);
-    prompt_run! { "Deploy argocd?", c, Expect::Success };
-
-    // TODO pause here for a couple of minutes while argo deploys
-    pause("Wait for a couple of minutes while the ELB comes up");
-
-    let argocd_server = kubectl::get_argo_server_name()?;
-    println!("\nDiscovered argocd-server pod: {}", &argocd_server);
-    let argo_elb = kubectl::get_argo_elb()?;
-    println!("\nDiscovered argocd-server elb: {}", &argo_elb);
-
-    println!("\nSkipping creation of DNS records for argocd or argocd-beta subdomain");
-    println!("https://github.com/paperlesspost/terraforming/pull/891");
-
-    // # Login to ArgoCD
-    let c = Cmd::new(vec![
-        "argocd",
-        "login",
-        &argo_elb,
-        "--username",
-        "admin",
-        "--password",
-        &argocd_server,
-    ]);
-    prompt_run!("Log in to argo?", c, Expect::Success);
-
-    //  Add our kubernetes-deployments repo to Argo
-    let repo = "git@github.com:paperlesspost/kubernetes-deployments";
-    let pk = &conf.kubernetes_deployments_ssh_key;
-    let c = Cmd::new(vec![
-        "argocd",
-        "repo",
-        "add",
-        repo,
-        "--ssh-private-key-path",
-        pk,
-    ]);
-    prompt_run!("Add git repo and private key?", c, Expect::Success);
-
-    // Patch argocd-secret
-    println!("\nThe argocd-secret must be patched with a value from 1Password");
-    println!("We will open a buffer in you editor and you will write this secret");
-    println!("to the first line. Do not write more than one line.");
-    if continue_prompt("Open buffer in your $EDITOR to input the secret?") {
-        if let Some(dex_secret) = Editor::new()
-            .edit("Enter 1P entry 'ArgoCD Beta Github App' (or equivalent) on exactly one line")
-            .unwrap()
-        {
-            let trimmed = dex_secret.trim();
-            // Brackets {} are annoying to escape, so we build each part: left, secret, right
-            let (left, right) = ("{ \"data\": { \"dex.github.clientSecret\": \"", "\"}}");
-            let patch = format!("{}{}{}", left, &trimmed, right);
-            let c = Cmd::new(vec![
-                "kubectl",
-                "patch",
-                "secret",
-                "argocd-secret",
-                "-n",
-                "argocd",
-                "--patch",
-                &patch,
-            ]);
-            prompt_run!("Patch argocd-secret?", c, Expect::Success);
-        } else {
-            println!("You must enter a dex secret. Exiting.");
-            std::process::exit(1);
+fn runs(conf: &Config, args: &clap::ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("list", _) => {
+            for (cluster_id, id) in journal::list(&conf.assets_cache_path)? {
+                println!("{}  {}", cluster_id, id);
+            }
+        }
+        ("show", Some(args)) => {
+            let id = args.value_of("id").unwrap();
+            let path = journal::find(&conf.assets_cache_path, id)?;
+            print!("{}", journal::render(&journal::read(path)?));
         }
+        _ => return Err(anyhow!("you must provide a runs subcommand")),
     }
+    Ok(())
+}
 
-    // Create a bootstrap project
-    let c = Cmd::new(vec![
-        "argocd",
-        "proj",
-        "create",
-        "bootstrap",
-        "-d",
-        "*,*",
-        "-s",
-        "*",
-    ]);
-    prompt_run!("Create argocd bootstrap project?", c, Expect::Success);
-
-    pause("Wait a few seconds and let the bootstrap project initialize");
-
-    // # Allow bootstrap project to manage any k8s resource GROUP and KIND
-    // argocd proj allow-cluster-resource bootstrap "*" "*"
-    let c = Cmd::new(vec![
-        "argocd",
-        "proj",
-        "allow-cluster-resource",
-        "bootstrap",
-        "*",
-        "*",
-    ]);
-    prompt_run!(
-        "Let bootstrap project manage any k8s resource?",
-        c,
-        Expect::Success
-    );
+/// Load the workflow `name` for the cluster `ctx`, with its steps repeated for
+/// each of the cluster's extra namespaces.
+fn load_workflow(conf: &Config, ctx: &ClusterContext, name: &str) -> Result<Workflow, Error> {
+    let mut workflow = workflows::load(name, conf)?.ok_or(anyhow!("{} is not a workflow", name))?;
+    workflow.for_each_namespace(&ctx.namespaces);
+    workflow.policy = Policy::load(conf)?;
+    Ok(workflow)
+}
 
-    // Launch platform services
-    let cluster_app_manifest = format!("bootstrap/{}/cluster.yaml", d_ns);
-    let mut c = Cmd::new(vec!["argocd", "app", "create", "-f", &cluster_app_manifest]);
-    let c = c.dir(path.clone());
-    prompt_run!(
-        "Create bootstrap Application CRD for cluster services (this will launch a bunch of pods)?",
-        c,
-        Expect::Success
-    );
+/// Pick the cluster to work on and start journaling the workflow `name`.
+/// Returns the cluster's context and the variables every workflow's commands
+/// may refer to.
+fn start_workflow(
+    conf: &Config,
+    session: &Session,
+    name: &str,
+    cluster_id: Option<String>,
+) -> Result<(ClusterContext, Vars), Error> {
+    let cluster_id = match cluster_id {
+        Some(id) => id,
+        None => pick_cluster_id_prompt(conf, session)?,
+    };
+    let ctx = ClusterContext::new(conf, &cluster_id)?;
+    session.start_workflow(&conf.assets_cache_path, &cluster_id, name)?;
+    let mut vars = playbook::config_vars(conf);
+    vars.extend(ctx.vars());
+    Ok((ctx, vars))
+}
 
-    pause("Wait for a minute for chartmuseum to come online");
-
-    // Patch argocd-cm config map
-    let left = "{ \"data\": { \"helm.repositories\": \"- name: paperless\\n  type: helm\\n  url: http://chartmuseum.";
-    let right = "\\n\"}}";
-    let patch = format!("{}{}{}", left, d_ns, right);
-    let c = Cmd::new(vec![
-        "kubectl",
-        "patch",
-        "configmap",
-        "argocd-cm",
-        "-n",
-        "argocd",
-        "--patch",
-        &patch,
-    ]);
-    prompt_run!(
-        "Patch argocd-cm configmap with our cluster's chartmuseum url?",
-        c,
-        Expect::Success
-    );
+/// Run every step of `workflow`, or only `step`. Returns true if the whole
+/// workflow ran to the end.
+fn run_workflow(
+    session: &Session,
+    workflow: &Workflow,
+    step: Option<&str>,
+    ctx: &ClusterContext,
+    vars: &mut Vars,
+) -> Result<bool, Error> {
+    match step {
+        Some(name) => {
+            workflow.run_step(session, ctx, name, vars)?;
+            Ok(false)
+        }
+        None => workflow.run(session, ctx, vars),
+    }
+}
 
-    // Deploy heapster
-    let heapster_path = "/tmp/pp-heapster.yaml";
-    let mut f = std::fs::File::create(&heapster_path)?;
-    let tmpl = heapster::heapster_app_template(&d_ns, &cluster_id);
-    f.write_all(&tmpl.into_bytes())?;
-    println!("\nAn Application CRD template has been written to /tmp/pp-heapster.yaml");
-    let c = Cmd::new(vec!["argocd", "app", "create", "-f", &heapster_path]);
-    prompt_run!("Deploy heapster?", c, Expect::Success);
-
-    // Deploy paperless services
-    println!("\n We are ready to deploy paperless services");
-    let pp_svcs_manifest = format!("bootstrap/{}/paperless-services.yaml", d_ns);
-    let mut c = Cmd::new(vec!["argocd", "app", "create", "-f", &pp_svcs_manifest]);
-    let c = c.dir(path.clone());
-    prompt_run!(
-        "Deploy pp services (this will launch all our apps)?",
-        c,
-        Expect::Success
-    );
+/// Download the cluster's kubeconfig and point every command run in `ctx` at
+/// it.
+fn use_kubeconfig(conf: &Config, session: &Session, ctx: &mut ClusterContext) -> Result<(), Error> {
+    let bucket = assets_bucket_name(session, ctx)?;
+    let cache_dir = Path::new(&conf.assets_cache_path).join(&ctx.cluster_id);
+    session.create_dir(&cache_dir)?;
+    let kubeconfig_path = cache_dir.join("kubeconfig");
+    let path = kubeconfig_path
+        .to_str()
+        .ok_or(anyhow!("malformed assets path"))?;
+    download_kubeconfig(session, ctx, &bucket, path)?;
 
-    println!("\nAll services deployed.");
+    ctx.kubeconfig = Some(kubeconfig_path.clone());
+    println!("\nUsing KUBECONFIG={} for every command below", path);
+    Ok(())
+}
 
+fn playbooks(conf: &Config, args: &clap::ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("list", _) => {
+            for name in playbook::names() {
+                if let Some((source, _)) = playbook::find(name, conf)? {
+                    println!("{:<16} {}", name, source);
+                }
+            }
+        }
+        ("show", Some(args)) => {
+            let name = args.value_of("name").unwrap();
+            if let Some((_, toml)) = playbook::find(name, conf)? {
+                print!("{}", toml);
+            }
+        }
+        _ => return Err(anyhow!("you must provide a playbooks subcommand")),
+    }
     Ok(())
 }
 
-fn namespace_init(conf: &Config, cluster_id: Option<String>) -> Result<(), Error> {
-    let cluster_id = cluster_id.unwrap_or(pick_cluster_id_prompt()?);
-    let infra_profile = &conf.infra_profile;
+fn config_subcommand(conf: &Config, args: &clap::ArgMatches) -> Result<(), Error> {
+    match args.subcommand() {
+        ("check", _) => {
+            let aws_config = check::aws_config_path(Path::new(&home_with("")));
+            let checks = check::check_config(conf, &aws_config);
+            print!("{}", check::report(&checks));
+            let failed = checks.iter().filter(|c| !c.passed()).count();
+            if failed > 0 {
+                return Err(anyhow!("{} config checks failed", failed));
+            }
+        }
+        ("show", _) => print!("{}", conf.show()?),
+        _ => return Err(anyhow!("you must provide a config subcommand")),
+    }
+    Ok(())
+}
 
-    // fetch kubeconfig
-    let bucket = assets_bucket_name(&cluster_id, infra_profile)?;
-    let cache_dir = Path::new(&conf.assets_cache_path).join(&cluster_id);
-    create_dir(&cache_dir)?;
-    let kubeconfig_path = cache_dir.join("kubeconfig");
-    let path = kubeconfig_path
-        .to_str()
-        .ok_or(anyhow!("malformed assets path"))?;
-    download_kubeconfig(&bucket, infra_profile, path)?;
-
-    // Child processes will inherit our custom KUBECONFIG
-    env::remove_var("KUBECONFIG");
-    env::set_var("KUBECONFIG", &path);
-
-    // create namespace
-    let d_ns = default_namespace(&cluster_id);
-    let c = Cmd::new(vec!["kubectl", "create", "ns", d_ns]);
-    prompt_run! { "Execute?", c, Expect::Success };
-
-    let c = Cmd::new(vec!["kubectl", "create", "ns", "mars"]);
-    prompt_run! { "Execute?", c, Expect::Success };
-
-    let secure_manifests = &conf.keybase_secure_manifests_path;
-    let shared_secrets = Path::new(secure_manifests).join("secrets/shared");
-    let default_ns_secrets = Path::new(secure_manifests).join(format!("secrets/{}", d_ns));
-    let default_ns_configmaps = Path::new(secure_manifests).join(format!("configMaps/{}", d_ns));
-    let shared_configmaps = Path::new(secure_manifests).join("configMaps/shared");
-    let mars_secrets = Path::new(secure_manifests).join("secrets/mars");
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "-n",
-        "kube-system",
-        "-Rf",
-        shared_secrets.to_str().unwrap(),
-    ]);
-    prompt_run! { "Deploy shared secrets?", c, Expect::Success }
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "-n",
-        d_ns,
-        "-Rf",
-        shared_configmaps.to_str().unwrap(),
-    ]);
-    prompt_run! { "Deploy shared config maps?", c, Expect::Success }
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "-n",
-        d_ns,
-        "-Rf",
-        default_ns_secrets.to_str().unwrap(),
-    ]);
-    prompt_run! { "Deploy default namespace secrets? NOTE: An error is expected", c, Expect::Failure }
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "-n",
-        d_ns,
-        "-Rf",
-        default_ns_configmaps.to_str().unwrap(),
-    ]);
-    prompt_run! { "Deploy default namespace config maps? NOTE: An error is expected", c, Expect::Failure }
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "-n",
-        "mars",
-        "-Rf",
-        mars_secrets.to_str().unwrap(),
-    ]);
-    prompt_run! { "Deploy mars namespace secrets? NOTE: An error is expected", c, Expect::Failure }
-
-    let from_literal = format!("--from-literal=cluster-name={}", &cluster_id);
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "configmap",
-        "cluster-info",
-        &from_literal,
-        "-n",
-        "kube-system",
-    ]);
-    prompt_run! { "Create cluster-info config map in kube-system namespace?", c, Expect::Success }
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "configmap",
-        "cluster-info",
-        &from_literal,
-        "-n",
-        &d_ns,
-    ]);
-    prompt_run! { "Create cluster-info config map in default namespace?", c, Expect::Success }
-
-    let c = Cmd::new(vec![
-        "kubectl",
-        "create",
-        "configmap",
-        "cluster-info",
-        &from_literal,
-        "-n",
-        "mars",
-    ]);
-    prompt_run! { "Create cluster-info config map in mars namespace?", c, Expect::Success }
+fn argo_init(
+    conf: &Config,
+    session: &Session,
+    cluster_id: Option<String>,
+    step: Option<&str>,
+) -> Result<(), Error> {
+    let (mut ctx, mut vars) = start_workflow(conf, session, "argo-init", cluster_id)?;
+    // the playbook, charts and apps below all come from this checkout
+    git::pin(
+        session,
+        &conf.kubernetes_deployments_path,
+        &conf.kubernetes_deployments_revision,
+    )?;
+    let workflow = load_workflow(conf, &ctx, "argo-init")?;
+    if conf.argocd_insecure {
+        eprintln!(
+            "{}",
+            Style::new()
+                .yellow()
+                .apply_to("argocd_insecure is set: argocd's certificate will not be verified")
+        );
+    }
+    use_kubeconfig(conf, session, &mut ctx)?;
+
+    if run_workflow(session, &workflow, step, &ctx, &mut vars)? {
+        println!("\nAll services deployed.");
+    }
+    Ok(())
+}
 
+fn namespace_init(
+    conf: &Config,
+    session: &Session,
+    cluster_id: Option<String>,
+    step: Option<&str>,
+) -> Result<(), Error> {
+    let (mut ctx, mut vars) = start_workflow(conf, session, "namespace-init", cluster_id)?;
+    let workflow = load_workflow(conf, &ctx, "namespace-init")?;
+    use_kubeconfig(conf, session, &mut ctx)?;
+
+    run_workflow(session, &workflow, step, &ctx, &mut vars)?;
     Ok(())
 }
 
-fn launch_cluster(conf: &Config) -> Result<(), Error> {
+fn launch_cluster(
+    conf: &Config,
+    session: &Session,
+    cluster_id: Option<String>,
+    step: Option<&str>,
+) -> Result<(), Error> {
     println!(
         r#"
 This will step you through launching a cluster.
@@ -435,117 +444,31 @@ This will step you through launching a cluster.
 3. STDOUT and STDERR will be printed to your console, as if you'd run the commands manually.
 "#
     );
-    let infra_profile = &conf.infra_profile;
-    let cluster_id = pick_cluster_id_prompt()?;
-    let path = Path::new(&conf.terraforming_path.clone()).join("projects/kubernetes-tectonic");
-
-    let mut c = Cmd::new(vec!["terraform", "get", "-update"]);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Success};
-
-    println!("\nSelect the correct workspace");
-    let mut c = Cmd::new(vec!["terraform", "workspace", "select", &cluster_id]);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Success};
-
-    println!("\nPlan changes to kubernetes-tectonic");
-    let tfvars = format!("{}.tfvars", &cluster_id);
-    let mut c = Cmd::new(vec![
-        "terraform",
-        "plan",
-        "-out",
-        "tfplan.out",
-        "-var-file",
-        &tfvars,
-    ]);
-    let c = c.env("AWS_PROFILE", &infra_profile);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Success};
-
-    println!("\nApply kubernetes-tectonic");
-    let mut c = Cmd::new(vec!["terraform", "apply", "tfplan.out"]);
-    let c = c.env("AWS_PROFILE", &infra_profile);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Failure};
-
-    println!("\nRe-plan changes to kubernetes-tectonic after expected error");
-    let mut c = Cmd::new(vec![
-        "terraform",
-        "plan",
-        "-out",
-        "tfplan.out",
-        "-var-file",
-        &tfvars,
-    ]);
-    let c = c.env("AWS_PROFILE", &infra_profile);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Success};
-
-    println!("\nRe-apply kubernetes-tectonic");
-    let mut c = Cmd::new(vec!["terraform", "apply", "tfplan.out"]);
-    let c = c.env("AWS_PROFILE", &infra_profile);
-    let c = c.dir(path.clone());
-    prompt_run! {"Execute command?", c, Expect::Success};
-
-    println!("\nEnjoy your new cluster :)");
+    let (ctx, mut vars) = start_workflow(conf, session, "launch-cluster", cluster_id)?;
+    let workflow = load_workflow(conf, &ctx, "launch-cluster")?;
 
+    if run_workflow(session, &workflow, step, &ctx, &mut vars)? {
+        println!("\nEnjoy your new cluster :)");
+    }
     Ok(())
 }
 
-fn destroy_kubernetes_ingress(conf: &Config, cluster_id: Option<String>) -> Result<(), Error> {
-    let cluster_id = cluster_id.unwrap_or(pick_cluster_id_prompt()?);
-    let path = Path::new(&conf.terraforming_path.clone()).join("projects/kubernetes-ingress");
-    let v1_profile = &conf.v1_profile;
-
-    println!("\nWe will now step through destroying the kubernetes-ingress project");
-    println!("First, we must select the right workspace");
-    println!("Path: {:?}", path);
-    println!("Command: terraform workspace select {}", cluster_id);
-    if continue_prompt("Execute command?") {
-        let status = terraform::workspace_select(&path, &cluster_id, &v1_profile)?;
-        if !status.success() {
-            return Err(anyhow!("could not select workspace"));
-        }
-    } else {
-        return Ok(());
-    }
-
-    println!(
-        "\nWe will now prepare a -destroy plan against terraforming/projects/kubernetes-ingress"
-    );
-    println!("Path: {:?}", path);
-    println!(
-        "Command: terraform plan -out tfplan.out -var-file {}.tfvars -destroy -detailed-exitcode",
-        cluster_id
-    );
-    if continue_prompt("Execute command?") {
-        let status = terraform::plan_destroy_with_tfvars_file(&path, &cluster_id, v1_profile)?;
-        match status.code() {
-            Some(1) => return Err(anyhow!("unexpected error in -destroy plan")),
-            _ => { /* no op - continue */ }
-        }
-    } else {
-        return Ok(());
-    }
-
-    println!(
-        "We are ready to apply. This will DESTROY DNS routes that point to {}",
-        cluster_id
-    );
-    println!("Path: {:?}", path);
-    println!("Command: terraform apply tfplan.out");
-    if continue_prompt("Execute command?") {
-        let status = terraform::apply(&path, v1_profile)?;
-        if !status.success() {
-            return Err(anyhow!("unexpected error"));
-        }
-    } else {
+fn destroy_kubernetes_ingress(
+    conf: &Config,
+    session: &Session,
+    cluster_id: Option<String>,
+    step: Option<&str>,
+) -> Result<(), Error> {
+    let (ctx, mut vars) = start_workflow(conf, session, "destroy-kubernetes-ingress", cluster_id)?;
+    let workflow = load_workflow(conf, &ctx, "destroy-kubernetes-ingress")?;
+    if !run_workflow(session, &workflow, step, &ctx, &mut vars)? {
         return Ok(());
     }
 
     println!("\nWe have removed the DNS records!");
 
-    let url = cluster_elbs_url(&cluster_id);
+    let cluster_id = &ctx.cluster_id;
+    let url = cluster_elbs_url(&ctx);
     println!(
         r#"
 A manual step is required in the AWS web console.
@@ -561,16 +484,22 @@ associated with the ELBs you delete.
         cluster_id, url
     );
 
-    if continue_prompt("Open this url in your browser? (remember to use the the infra profile)") {
+    if session.is_interactive()
+        && session
+            .confirm("Open this url in your browser? (remember to use the the infra profile)")?
+    {
         open_browser(&url)?;
     }
 
     Ok(())
 }
 
-fn destroy_cluster(conf: &Config) -> Result<(), Error> {
-    let theme = prompt_theme();
-
+fn destroy_cluster(
+    conf: &Config,
+    session: &Session,
+    cluster_id: Option<String>,
+    step: Option<&str>,
+) -> Result<(), Error> {
     println!(
         r#"
 This will step you through destroying a cluster.
@@ -579,148 +508,24 @@ This will step you through destroying a cluster.
 3. STDOUT and STDERR will be printed to your console, as if you'd run the commands manually.
 "#
     );
-    if !continue_prompt("Do you want to proceed? (Use arrows)") {
+    if !session.confirm("Do you want to proceed? (Use arrows)")? {
         return Ok(());
     }
-    println!("");
-
-    let infra_profile = &conf.infra_profile;
-    let cluster_id = pick_cluster_id_prompt()?;
-
-    // destroy kubernetes-alarms
-    // TODO
+    println!();
 
-    // destroy kubernetes-tectonic
-    let path = Path::new(&conf.terraforming_path.clone()).join("projects/kubernetes-tectonic");
-    println!(
-        "\nWe will now prepare a -destroy plan against terraforming/projects/kubernetes-tectonic"
-    );
-    println!("First, we must select the right workspace");
-    println!("Path: {:?}", path);
-    println!("Command: terraform workspace select {}", cluster_id);
-    if !continue_prompt("Execute command?") {
-        return Ok(());
-    }
-
-    let status = terraform::workspace_select(&path, &cluster_id, infra_profile)?;
-    if !status.success() {
-        return Err(anyhow!("terraform workspace select"));
-    }
-
-    println!("\nNext, we can optionally remove state that sometimes causes problems");
-    println!("Path: {:?}", path);
-    println!(
-        r#"Command: terraform state rm \ 
-    module.tectonic-aws.module.bootkube.template_dir.bootkube \
-    module.tectonic-aws.module.tectonic.template_dir.tectonic \
-    module.tectonic-aws.module.bootkube.template_dir.bootkube_bootstrap"#
-    );
-
-    let idx = Select::with_theme(&theme)
-        .with_prompt("Execute command or skip?")
-        .items(&["execute", "skip"])
-        .interact()?;
-
-    if idx == 0 {
-        let status = terraform::state_rm(
-            &path,
-            &[
-                "module.tectonic-aws.module.bootkube.template_dir.bootkube",
-                "module.tectonic-aws.module.tectonic.template_dir.tectonic",
-                "module.tectonic-aws.module.bootkube.template_dir.bootkube_bootstrap",
-            ],
-            infra_profile,
-        )?;
-        if !status.success() {
-            return Err(anyhow!("error: terraform state rm"));
-        }
-    }
-
-    println!("\nNext, we actually plan");
-    println!("Path: {:?}", path);
-    println!(
-        "Command: terraform plan -out tfplan.out -var-file {}.tfvars -destroy -detailed-exitcode",
-        cluster_id
-    );
-    if continue_prompt("Execute command?") {
-        let status = terraform::plan_destroy_with_tfvars_file(&path, &cluster_id, infra_profile)?;
-        // NOTE: we should be able to match on exit code 0 here to indicate no
-        // diff was found, but it does not seem to work. We get exit code 2,
-        // even when the plan shows no diff (e.g. -destroy against a cluster
-        // that doesn't exist).
-        match status.code() {
-            Some(1) => {
-                return Err(anyhow!(
-                    r#"Could not "terraform plan". 
-You probably need to re-run this tool and remove problematic bootkube/tectonic state."#
-                ));
-            }
-            _ => { /* no op - continue */ }
-        }
-    } else {
-        return Ok(());
-    }
-
-    println!("The plan we just ran should show approximately 120 resources to delete.");
-    println!(
-        r#"Unfortunately, the output of the plan does not contain the cluster id
-so we must double check the workspace we are on!
-"#
-    );
-    println!("Path: {:?}", path);
-    println!("Command: terraform workspace show");
-    if continue_prompt("Execute_command?") {
-        println!("");
-        let status = terraform::workspace_show(&path, infra_profile)?;
-        if !status.success() {
-            return Err(anyhow!("terraform workspace show"));
-        }
-    } else {
-        return Ok(());
-    }
-
-    if !continue_prompt("\nAre we on the right workspace?") {
-        return Err(anyhow!("wrong workspace"));
-    }
-
-    // destroy kubernetes-ingress
-    println!("\nWe are ready to destroy the cluster. THERE IS NO GOING BACK");
-    println!("Path: {:?}", path);
-    println!("Command: terraform apply tfplan.out");
-    if continue_prompt("Execute command?") {
-        let status = terraform::apply(&path, infra_profile)?;
-        if !status.success() {
-            println!("\nterraform apply encountered an error, but this is expected.");
-        }
-    } else {
-        return Ok(());
-    }
-
-    // TODO do we need TWO re-plan and re-applies?
-
-    println!("We will now create another -destroy plan to ensure all resources are cleaned up");
-    println!("This plan should show no diff");
-    println!("Path: {:?}", path);
-    println!(
-        "Command: terraform plan -out tfplan.out -var-file {}.tfvars -destroy -detailed-exitcode",
-        cluster_id
-    );
-    if continue_prompt("Execute command?") {
-        let status = terraform::plan_destroy_with_tfvars_file(&path, &cluster_id, infra_profile)?;
-        match status.code() {
-            Some(1) => return Err(anyhow!("unexpected error")),
-            _ => { /* no op - continue */ }
-        }
-    } else {
+    let (ctx, mut vars) = start_workflow(conf, session, "destroy-cluster", cluster_id)?;
+    let workflow = load_workflow(conf, &ctx, "destroy-cluster")?;
+    if !run_workflow(session, &workflow, step, &ctx, &mut vars)? {
         return Ok(());
     }
 
+    let cluster_id = &ctx.cluster_id;
     println!(
         "\nCluster destroy complete. DNS and ELBs associated with {} may still be up",
         cluster_id
     );
-    if continue_prompt("Do you want to move on to destroying DNS and ELBs?") {
-        destroy_kubernetes_ingress(conf, Some(cluster_id.to_owned()))?;
+    if session.confirm("Do you want to move on to destroying DNS and ELBs?")? {
+        destroy_kubernetes_ingress(conf, session, Some(cluster_id.to_owned()), None)?;
     }
 
     Ok(())
@@ -743,23 +548,13 @@ fn create_dir<P: AsRef<Path>>(p: P) -> Result<(), Error> {
     Ok(())
 }
 
-fn valid_clusters() -> Vec<&'static str> {
-    vec![
-        "development0",
-        "development1",
-        "development2",
-        "production0",
-        "production1",
-        "production2",
-    ]
-}
-
-fn assets_bucket_name(cluster_id: &str, profile: &str) -> Result<String, Error> {
+fn assets_bucket_name(session: &Session, ctx: &ClusterContext) -> Result<String, Error> {
+    if let Some(bucket) = &ctx.assets_bucket {
+        return Ok(bucket.clone());
+    }
+    let cluster_id = &ctx.cluster_id;
     let matcher = format!("a{}", cluster_id);
-    use std::str::FromStr;
-    let mut cmd = Command::new("aws");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.args(vec![
+    let cmd = ctx.aws(vec![
         "s3api",
         "list-buckets",
         "--query",
@@ -767,25 +562,25 @@ fn assets_bucket_name(cluster_id: &str, profile: &str) -> Result<String, Error>
         "--output",
         "text",
     ]);
-    let output = cmd.output()?;
-    if !output.status.success() {
-        // println!("STDOUT {:?}", std::str::from_utf8(&output.stderr)?);
-        // println!("STDERR {:?}", std::str::from_utf8(&output.stdout)?);
-        return Err(anyhow!("listing buckets with aws cli failed"));
-    }
-    let s = std::str::from_utf8(&output.stdout)?;
-    for item in s.split_whitespace() {
+    let placeholder = format!("{}-<assets-bucket>", matcher);
+    let buckets = session
+        .query(&cmd, &placeholder)
+        .context("listing buckets with aws cli failed")?;
+    for item in buckets.split_whitespace() {
         if item.starts_with(&matcher) {
-            return Ok(String::from_str(item)?);
+            return Ok(item.to_owned());
         }
     }
     Err(anyhow!("could not locate assets bucket for {}", cluster_id))
 }
 
-fn download_kubeconfig(bucket: &str, profile: &str, output: &str) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("aws");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.args(vec![
+fn download_kubeconfig(
+    session: &Session,
+    ctx: &ClusterContext,
+    bucket: &str,
+    output: &str,
+) -> Result<(), Error> {
+    let cmd = ctx.aws(vec![
         "s3api",
         "get-object",
         "--bucket",
@@ -794,35 +589,13 @@ fn download_kubeconfig(bucket: &str, profile: &str, output: &str) -> Result<Exit
         "kubeconfig",
         output,
     ]);
-    Ok(cmd.status()?)
-}
-fn pause(msg: &'static str) {
-    let theme = prompt_theme();
-    Select::with_theme(&theme)
-        .with_prompt(msg)
-        .items(&["I'm done waiting"])
-        .interact()
-        .unwrap();
-}
-
-fn continue_prompt(msg: &'static str) -> bool {
-    let theme = prompt_theme();
-    Select::with_theme(&theme)
-        .with_prompt(msg)
-        .items(&["yes", "no"])
-        .interact()
-        .unwrap()
-        == 0
-}
-
-fn prompt_theme() -> ColorfulTheme {
-    ColorfulTheme {
-        values_style: Style::new().yellow().dim(),
-        indicator_style: Style::new().yellow().bold(),
-        yes_style: Style::new().yellow().dim(),
-        no_style: Style::new().yellow().dim(),
-        ..ColorfulTheme::default()
+    println!("---\n{}", cmd.describe());
+    if let Some(outcome) = session.run("Download kubeconfig", &cmd, false)? {
+        if !outcome.status.success() {
+            return Err(anyhow!("could not download kubeconfig from {}", bucket));
+        }
     }
+    Ok(())
 }
 
 fn open_browser(url: &str) -> Result<(), Error> {
@@ -838,26 +611,13 @@ fn open_browser(url: &str) -> Result<(), Error> {
     Ok(())
 }
 
-fn cluster_elbs_url(cluster_id: &str) -> String {
-    format!("https://console.aws.amazon.com/ec2/home?region=us-east-1#LoadBalancers:tag:kubernetes.io/cluster/{}=*", cluster_id)
-}
-
-fn pick_cluster_id_prompt() -> Result<String, Error> {
-    let theme = prompt_theme();
-    let ids = valid_clusters();
-    let idx = Select::with_theme(&theme)
-        .with_prompt("Select a cluster id")
-        .items(&ids)
-        .interact()?;
-    Ok(ids[idx].to_owned())
+fn cluster_elbs_url(ctx: &ClusterContext) -> String {
+    format!(
+        "https://console.aws.amazon.com/ec2/home?region={}#LoadBalancers:tag:kubernetes.io/cluster/{}=*",
+        ctx.region, ctx.cluster_id
+    )
 }
 
-fn default_namespace(cluster_id: &str) -> &'static str {
-    if cluster_id.starts_with("development") {
-        return "development";
-    }
-    if cluster_id.starts_with("production") {
-        return "production";
-    }
-    unreachable!("unknown cluster id {}", cluster_id)
+fn pick_cluster_id_prompt(conf: &Config, session: &Session) -> Result<String, Error> {
+    session.pick_cluster_id(&conf.cluster_ids()?)
 }
@@ -0,0 +1,554 @@
+use crate::config::Config;
+use crate::runner::{Cmd, Expect, Retry};
+use crate::wait::{self, Condition, Until};
+use crate::workflow::{Step, Vars, Workflow};
+use anyhow::{anyhow, Context, Error};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Workflows that can be defined by a playbook, with the playbook that ships
+/// with clusterctl for each.
+const BUILT_IN: [(&str, &str); 3] = [
+    (
+        "launch-cluster",
+        include_str!("../playbooks/launch-cluster.toml"),
+    ),
+    (
+        "namespace-init",
+        include_str!("../playbooks/namespace-init.toml"),
+    ),
+    ("argo-init", include_str!("../playbooks/argo-init.toml")),
+];
+
+/// A workflow written in TOML. Commands, paths and files may refer to
+/// `{cluster_id}`, `{default_namespace}`, `{terraform_workspace}`, any
+/// config.toml setting such as `{kubernetes_deployments_path}`, and values
+/// read by earlier steps. A step whose name contains `{namespace}` runs once
+/// for each of the cluster's extra namespaces.
+///
+/// ```toml
+/// [[steps]]
+/// name = "create-mars-namespace"
+/// prompt = "Execute?"
+/// command = ["kubectl", "create", "ns", "mars"]
+/// expect = { failure_with_output = "AlreadyExists" }
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Playbook {
+    pub steps: Vec<StepDef>,
+}
+
+/// One step of a playbook. A step runs `command`, unless it has `query`,
+/// `write` or `edit`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepDef {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default = "default_prompt")]
+    pub prompt: String,
+    pub command: Option<Vec<String>>,
+    pub dir: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// File that the command's stdout is written to.
+    pub writes: Option<String>,
+    /// Fed to the command's stdin. Secrets belong here, never in `command`,
+    /// where any user on the machine can see them.
+    pub stdin: Option<String>,
+    #[serde(default)]
+    pub expect: ExpectDef,
+    pub query: Option<QueryDef>,
+    pub write: Option<WriteDef>,
+    pub edit: Option<EditDef>,
+    #[serde(default)]
+    pub required: bool,
+    pub hint: Option<String>,
+    pub retry: Option<RetryDef>,
+    /// Kill the command if it runs longer than this.
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub wait_until: Vec<UntilDef>,
+    pub wait: Option<String>,
+    pub verify: Option<String>,
+    /// Summarize the terraform plan that the command saves.
+    #[serde(default)]
+    pub summarize_plan: bool,
+    /// Fail unless the terraform plan that the command saves is empty.
+    #[serde(default)]
+    pub expect_empty_plan: bool,
+    /// How many resources the plan a `terraform apply` applies should delete.
+    pub review_plan: Option<ReviewPlanDef>,
+}
+
+fn default_prompt() -> String {
+    "Execute?".to_owned()
+}
+
+/// `Expect` as written in a playbook, e.g. `expect = "failure"` or
+/// `expect = { code_with_output = { code = 1, output = "AlreadyExists" } }`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpectDef {
+    Code(i32),
+    Codes(Vec<i32>),
+    Output(String),
+    CodeWithOutput {
+        code: i32,
+        output: String,
+    },
+    SuccessWithOutput(String),
+    FailureWithOutput(String),
+    #[default]
+    Success,
+    Failure,
+    Any,
+}
+
+impl From<ExpectDef> for Expect {
+    fn from(def: ExpectDef) -> Self {
+        match def {
+            ExpectDef::Code(code) => Expect::Code(code),
+            ExpectDef::Codes(codes) => Expect::Codes(codes),
+            ExpectDef::Output(pat) => Expect::Output(pat),
+            ExpectDef::CodeWithOutput { code, output } => Expect::CodeWithOutput((code, output)),
+            ExpectDef::SuccessWithOutput(pat) => Expect::SuccessWithOutput(pat),
+            ExpectDef::FailureWithOutput(pat) => Expect::FailureWithOutput(pat),
+            ExpectDef::Success => Expect::Success,
+            ExpectDef::Failure => Expect::Failure,
+            ExpectDef::Any => Expect::Any,
+        }
+    }
+}
+
+/// A retry policy, e.g. `retry = { attempts = 5, backoff_secs = 15 }`.
+/// Without `codes` or `output`, any unexpected result is retried.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryDef {
+    pub attempts: u32,
+    #[serde(default = "default_backoff_secs")]
+    pub backoff_secs: u64,
+    #[serde(default = "default_multiplier")]
+    pub multiplier: u32,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    #[serde(default)]
+    pub codes: Vec<i32>,
+    #[serde(default)]
+    pub output: Vec<String>,
+}
+
+fn default_backoff_secs() -> u64 {
+    5
+}
+
+fn default_multiplier() -> u32 {
+    2
+}
+
+fn default_max_backoff_secs() -> u64 {
+    300
+}
+
+impl From<RetryDef> for Retry {
+    fn from(def: RetryDef) -> Self {
+        Retry {
+            attempts: def.attempts,
+            backoff: Duration::from_secs(def.backoff_secs),
+            multiplier: def.multiplier,
+            max_backoff: Duration::from_secs(def.max_backoff_secs),
+            codes: def.codes,
+            output: def.output,
+        }
+    }
+}
+
+/// A condition to wait for after a step, e.g.
+/// `wait_until = [{ load_balancer = "argocd/argocd-server" }]`. Exactly one of
+/// `load_balancer`, `rollout`, `endpoints`, `argocd_project` or `command`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UntilDef {
+    /// `namespace/service`
+    pub load_balancer: Option<String>,
+    /// `namespace/deployment`
+    pub rollout: Option<String>,
+    /// `namespace/service`
+    pub endpoints: Option<String>,
+    pub argocd_project: Option<String>,
+    pub command: Option<Vec<String>>,
+    #[serde(default = "default_wait_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_wait_secs() -> u64 {
+    600
+}
+
+fn default_interval_secs() -> u64 {
+    10
+}
+
+impl UntilDef {
+    fn into_until(self, step: &str) -> Result<Until, Error> {
+        let namespaced = |s: String| {
+            wait::namespaced(&s)
+                .ok_or_else(|| anyhow!("step {} waits on {:?}, not namespace/name", step, s))
+        };
+        let condition = match (
+            self.load_balancer,
+            self.rollout,
+            self.endpoints,
+            self.argocd_project,
+            self.command,
+        ) {
+            (Some(s), None, None, None, None) => {
+                let (ns, name) = namespaced(s)?;
+                Condition::LoadBalancer(ns, name)
+            }
+            (None, Some(s), None, None, None) => {
+                let (ns, name) = namespaced(s)?;
+                Condition::Rollout(ns, name)
+            }
+            (None, None, Some(s), None, None) => {
+                let (ns, name) = namespaced(s)?;
+                Condition::Endpoints(ns, name)
+            }
+            (None, None, None, Some(project), None) => Condition::ArgoProject(project),
+            (None, None, None, None, Some(command)) if !command.is_empty() => {
+                Condition::Command(Cmd::new(command))
+            }
+            _ => {
+                return Err(anyhow!(
+                    "each wait_until of step {} must have exactly one of load_balancer, \
+                     rollout, endpoints, argocd_project or command",
+                    step
+                ))
+            }
+        };
+        Ok(Until {
+            condition,
+            timeout: Duration::from_secs(self.timeout_secs),
+            interval: Duration::from_secs(self.interval_secs),
+        })
+    }
+}
+
+/// What a plan is expected to delete, e.g. `review_plan = { max_deletes = 0 }`.
+/// A plan outside the bounds is flagged before it is applied.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReviewPlanDef {
+    #[serde(default)]
+    pub min_deletes: usize,
+    pub max_deletes: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueryDef {
+    pub var: String,
+    #[serde(default)]
+    pub placeholder: String,
+    /// Mask the value wherever commands are shown, e.g. for a password.
+    #[serde(default)]
+    pub sensitive: bool,
+    /// Keep only this field of a JSON object printed by the command.
+    pub json_field: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteDef {
+    pub path: String,
+    pub contents: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EditDef {
+    pub var: String,
+    pub msg: String,
+}
+
+impl StepDef {
+    fn into_step(self) -> Result<Step, Error> {
+        let name = self.name;
+        let step = match (self.command, self.query, self.write, self.edit) {
+            (Some(command), query, None, None) => {
+                if command.is_empty() {
+                    return Err(anyhow!("step {} has an empty command", name));
+                }
+                let mut cmd = Cmd::new(command);
+                if let Some(dir) = self.dir {
+                    cmd.dir(PathBuf::from(dir));
+                }
+                for (k, v) in &self.env {
+                    cmd.env(k, v);
+                }
+                if let Some(path) = self.writes {
+                    cmd.writes_file(PathBuf::from(path));
+                }
+                if let Some(data) = &self.stdin {
+                    cmd.stdin(data);
+                }
+                if let Some(secs) = self.timeout_secs {
+                    cmd.timeout(Duration::from_secs(secs));
+                }
+                match query {
+                    Some(q) => {
+                        let mut step = Step::query(&name, cmd, &q.var, &q.placeholder);
+                        if q.sensitive {
+                            step = step.sensitive();
+                        }
+                        if let Some(field) = &q.json_field {
+                            step = step.json_field(field);
+                        }
+                        step
+                    }
+                    None => Step::run(&name, &self.prompt, cmd, self.expect.into()),
+                }
+            }
+            (None, None, Some(write), None) => {
+                Step::write_file(&name, PathBuf::from(write.path), write.contents)
+            }
+            (None, None, None, Some(edit)) => Step::edit(&name, &self.prompt, &edit.msg, &edit.var),
+            _ => {
+                return Err(anyhow!(
+                    "step {} must have exactly one of command, write or edit",
+                    name
+                ))
+            }
+        };
+        let until = self
+            .wait_until
+            .into_iter()
+            .map(|def| def.into_until(&name))
+            .collect::<Result<Vec<Until>, Error>>()?;
+        let step = Step {
+            description: self.description,
+            until,
+            required: self.required,
+            hint: self.hint,
+            retry: self.retry.map(Into::into),
+            wait: self.wait,
+            verify: self.verify,
+            summarize_plan: self.summarize_plan || self.expect_empty_plan,
+            expect_empty_plan: self.expect_empty_plan,
+            review_plan: self
+                .review_plan
+                .map(|r| r.min_deletes..=r.max_deletes.unwrap_or(usize::MAX)),
+            ..step
+        };
+        Ok(step)
+    }
+}
+
+/// Where a playbook was found.
+pub enum Source {
+    File(PathBuf),
+    BuiltIn,
+}
+
+impl std::fmt::Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::BuiltIn => write!(f, "built-in"),
+        }
+    }
+}
+
+/// Names of the workflows that playbooks can define.
+pub fn names() -> Vec<&'static str> {
+    BUILT_IN.iter().map(|(name, _)| *name).collect()
+}
+
+/// The places a playbook for `name` is looked for, in order: next to
+/// config.toml, then in the kubernetes-deployments repo.
+pub fn search_paths(name: &str, conf: &Config) -> Vec<PathBuf> {
+    let file = format!("{}.toml", name);
+    vec![
+        conf.config_dir.join("playbooks").join(&file),
+        Path::new(&conf.kubernetes_deployments_path)
+            .join("clusterctl/playbooks")
+            .join(&file),
+    ]
+}
+
+/// Find the playbook in effect for `name` and return its TOML.
+pub fn find(name: &str, conf: &Config) -> Result<Option<(Source, String)>, Error> {
+    let built_in = match BUILT_IN.iter().find(|(n, _)| *n == name) {
+        Some((_, toml)) => toml,
+        None => return Ok(None),
+    };
+    for path in search_paths(name, conf) {
+        if path.exists() {
+            let s = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read playbook {:?}", path))?;
+            return Ok(Some((Source::File(path), s)));
+        }
+    }
+    Ok(Some((Source::BuiltIn, built_in.to_string())))
+}
+
+/// Load the workflow `name` from its playbook, if it is defined by one.
+pub fn load(name: &str, conf: &Config) -> Result<Option<Workflow>, Error> {
+    match find(name, conf)? {
+        Some((source, s)) => {
+            if let Source::File(path) = &source {
+                println!("Using playbook {}", path.display());
+            }
+            let workflow = parse(name, &s).with_context(|| format!("playbook {}", source))?;
+            Ok(Some(workflow))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn parse(name: &str, s: &str) -> Result<Workflow, Error> {
+    let playbook: Playbook = toml::from_str(s)?;
+    let mut steps = vec![];
+    for def in playbook.steps {
+        if steps.iter().any(|s: &Step| s.name == def.name) {
+            return Err(anyhow!("more than one step is named {}", def.name));
+        }
+        steps.push(def.into_step()?);
+    }
+    Ok(Workflow::new(name, steps))
+}
+
+/// Variables for every config.toml setting, so that playbooks can refer to
+/// paths and profiles.
+pub fn config_vars(conf: &Config) -> Vars {
+    let mut vars = Vars::new();
+    let settings = [
+        ("terraforming_path", &conf.terraforming_path),
+        (
+            "kubernetes_deployments_path",
+            &conf.kubernetes_deployments_path,
+        ),
+        (
+            "keybase_secure_manifests_path",
+            &conf.keybase_secure_manifests_path,
+        ),
+        (
+            "kubernetes_deployments_revision",
+            &conf.kubernetes_deployments_revision,
+        ),
+        (
+            "kubernetes_deployments_ssh_key",
+            &conf.kubernetes_deployments_ssh_key,
+        ),
+        ("infra_profile", &conf.infra_profile),
+        ("v1_profile", &conf.v1_profile),
+        ("assets_cache_path", &conf.assets_cache_path),
+        ("region", &conf.region),
+    ];
+    for (k, v) in settings.iter() {
+        vars.insert(k.to_string(), v.to_string());
+    }
+    // curl needs a flag either way; `--no-insecure` turns verification back on
+    let (argocd_opts, curl_tls) = if conf.argocd_insecure {
+        ("--insecure", "--insecure")
+    } else {
+        ("", "--no-insecure")
+    };
+    vars.insert("argocd_opts".to_owned(), argocd_opts.to_owned());
+    vars.insert("curl_tls".to_owned(), curl_tls.to_owned());
+    vars
+}
+
+#[test]
+fn test_built_in_playbooks() {
+    for (name, toml) in BUILT_IN.iter() {
+        let workflow = parse(name, toml).unwrap();
+        assert!(!workflow.steps.is_empty(), "{}", name);
+    }
+}
+
+#[test]
+fn test_parse_playbook() {
+    let workflow = parse(
+        "namespace-init",
+        r#"
+[[steps]]
+name = "create-mars-namespace"
+command = ["kubectl", "create", "ns", "mars"]
+expect = { code_with_output = { code = 1, output = "AlreadyExists" } }
+
+[[steps]]
+name = "find-elb"
+command = ["kubectl", "get", "svc"]
+query = { var = "elb" }
+
+[[steps]]
+name = "deploy-argocd"
+command = ["kubectl", "apply", "-f", "argo.yaml"]
+wait_until = [
+    { load_balancer = "argocd/argocd-server", timeout_secs = 900 },
+    { argocd_project = "bootstrap" },
+]
+"#,
+    )
+    .unwrap();
+    assert_eq!(workflow.steps.len(), 3);
+    assert_eq!(workflow.steps[2].until.len(), 2);
+    assert_eq!(
+        workflow.steps[2].until[0].condition.to_string(),
+        "service argocd/argocd-server has a load balancer hostname"
+    );
+    assert_eq!(workflow.steps[0].prompt, "Execute?");
+
+    let err = parse(
+        "namespace-init",
+        r#"
+[[steps]]
+name = "nothing"
+"#,
+    )
+    .err()
+    .unwrap();
+    assert!(err.to_string().contains("exactly one of"));
+
+    assert!(parse(
+        "namespace-init",
+        r#"
+[[steps]]
+name = "typo"
+comand = ["ls"]
+"#,
+    )
+    .is_err());
+
+    assert!(parse(
+        "argo-init",
+        r#"
+[[steps]]
+name = "no-namespace"
+command = ["ls"]
+wait_until = [{ rollout = "argocd-server" }]
+"#,
+    )
+    .is_err());
+}
+
+#[test]
+fn test_argocd_insecure() {
+    let mut conf = crate::config::test_config();
+    // argocd's certificate is verified unless the config says otherwise
+    let vars = config_vars(&conf);
+    assert_eq!(vars["curl_tls"], "--no-insecure");
+    assert_eq!(vars["argocd_opts"], "");
+
+    conf.argocd_insecure = true;
+    let vars = config_vars(&conf);
+    assert_eq!(vars["curl_tls"], "--insecure");
+    assert_eq!(vars["argocd_opts"], "--insecure");
+}
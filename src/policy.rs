@@ -0,0 +1,254 @@
+use crate::config::Config;
+use crate::context::ClusterContext;
+use crate::terraform::PlanSummary;
+use anyhow::{Context, Error};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Rules every terraform plan must follow before clusterctl applies it, e.g.
+///
+/// ```toml
+/// [policy]
+/// protected_types = ["aws_route53_zone"]
+/// cluster_tag = "KubernetesCluster"
+/// untagged_types = ["aws_route53_record"]
+/// max_deletes = { production = 10 }
+/// ```
+///
+/// A plan that breaks them is only applied with `--override-policy`.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Resource types no plan may delete.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protected_types: Vec<String>,
+    /// A tag whose value must be the cluster id on every resource a plan
+    /// deletes. A resource without the tag breaks the rule too, unless its
+    /// type is one of `untagged_types`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cluster_tag: Option<String>,
+    /// Resource types that cannot carry `cluster_tag`, such as DNS records,
+    /// and may be deleted without it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub untagged_types: Vec<String>,
+    /// The most resources a plan may delete, by cluster environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub max_deletes: BTreeMap<String, usize>,
+}
+
+/// Where the terraforming repo keeps the rules for its projects.
+pub fn repo_path(conf: &Config) -> PathBuf {
+    Path::new(&conf.terraforming_path).join("clusterctl/policy.toml")
+}
+
+impl Policy {
+    /// The rules in the terraforming repo, if it has any, together with those
+    /// in config.toml, which can only tighten them. Protected types add up and
+    /// the lower delete limit wins. config.toml's `cluster_tag` and
+    /// `untagged_types` only count when the repo sets no `cluster_tag`.
+    pub fn load(conf: &Config) -> Result<Self, Error> {
+        let path = repo_path(conf);
+        let mut policy = if path.exists() {
+            let s = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read {}", path.display()))?;
+            toml::from_str(&s)
+                .with_context(|| format!("policy parsing error in {}", path.display()))?
+        } else {
+            Policy::default()
+        };
+        for ignored in policy.merge(&conf.policy) {
+            eprintln!(
+                "Ignoring {} in config.toml, which would loosen the policy in {}",
+                ignored,
+                path.display()
+            );
+        }
+        Ok(policy)
+    }
+
+    /// Add `other`'s rules to these, returning those that were left out
+    /// because they would loosen them.
+    fn merge(&mut self, other: &Policy) -> Vec<String> {
+        let mut ignored = vec![];
+        for t in &other.protected_types {
+            if !self.protected_types.contains(t) {
+                self.protected_types.push(t.clone());
+            }
+        }
+        match &self.cluster_tag {
+            None => {
+                self.cluster_tag = other.cluster_tag.clone();
+                for t in &other.untagged_types {
+                    if !self.untagged_types.contains(t) {
+                        self.untagged_types.push(t.clone());
+                    }
+                }
+            }
+            Some(tag) => {
+                if let Some(theirs) = other.cluster_tag.as_ref().filter(|t| *t != tag) {
+                    ignored.push(format!("cluster_tag = {:?}", theirs));
+                }
+                for t in &other.untagged_types {
+                    if !self.untagged_types.contains(t) {
+                        ignored.push(format!("{} in untagged_types", t));
+                    }
+                }
+            }
+        }
+        for (env, max) in &other.max_deletes {
+            let limit = self.max_deletes.entry(env.clone()).or_insert(*max);
+            *limit = (*limit).min(*max);
+        }
+        ignored
+    }
+
+    /// Every way `plan` breaks the rules on the cluster `ctx`.
+    pub fn violations(&self, plan: &PlanSummary, ctx: &ClusterContext) -> Vec<String> {
+        let mut violations = vec![];
+        for rc in plan.changes.iter().filter(|rc| rc.deletes()) {
+            if self.protected_types.contains(&rc.resource_type) {
+                violations.push(format!(
+                    "{} would be deleted; {} is protected",
+                    rc.address, rc.resource_type
+                ));
+            }
+            let tag = match &self.cluster_tag {
+                Some(tag) if !self.untagged_types.contains(&rc.resource_type) => tag,
+                _ => continue,
+            };
+            match rc.tags().and_then(|mut tags| tags.remove(tag)) {
+                Some(value) if value == ctx.cluster_id => {}
+                Some(_) => violations.push(format!(
+                    "{} would be deleted but is not tagged {} = {}",
+                    rc.address, tag, ctx.cluster_id
+                )),
+                None => violations.push(format!(
+                    "{} would be deleted but has no {} tag; list {} in untagged_types if it cannot have one",
+                    rc.address, tag, rc.resource_type
+                )),
+            }
+        }
+        if let Some(max) = self.max_deletes.get(&ctx.environment) {
+            if plan.total.delete > *max {
+                violations.push(format!(
+                    "the plan deletes {} resources; {} plans may delete at most {}",
+                    plan.total.delete, ctx.environment, max
+                ));
+            }
+        }
+        violations
+    }
+}
+
+#[test]
+fn test_policy_violations() {
+    let conf: Config = toml::from_str(&crate::config::test_config_toml(
+        r#"
+[policy]
+protected_types = ["aws_route53_zone"]
+cluster_tag = "KubernetesCluster"
+untagged_types = ["aws_iam_role"]
+max_deletes = { production = 2 }
+
+[clusters.production1]
+environment = "production"
+"#,
+    ))
+    .unwrap();
+    let plan = PlanSummary::from_json(
+        r#"{"resource_changes": [
+    {"address": "aws_route53_zone.cluster", "type": "aws_route53_zone",
+     "change": {"actions": ["delete"], "before": {"name": "k8s.example.com"}}},
+    {"address": "aws_instance.master", "type": "aws_instance",
+     "change": {"actions": ["delete"], "before": {"tags": {"KubernetesCluster": "development1"}}}},
+    {"address": "aws_instance.other", "type": "aws_instance",
+     "change": {"actions": ["delete"], "before": {"tags": {"KubernetesCluster": "development2"}}}},
+    {"address": "aws_route53_record.api", "type": "aws_route53_record",
+     "change": {"actions": ["delete"], "before": {"name": "api.k8s.example.com"}}},
+    {"address": "aws_iam_role.master", "type": "aws_iam_role",
+     "change": {"actions": ["delete"], "before": {"name": "master"}}},
+    {"address": "aws_instance.new", "type": "aws_instance",
+     "change": {"actions": ["create"], "before": null}}
+]}"#,
+    )
+    .unwrap();
+    let policy = Policy::load(&conf).unwrap();
+
+    let dev = ClusterContext::new(&conf, "development1").unwrap();
+    assert_eq!(
+        policy.violations(&plan, &dev),
+        vec![
+            "aws_route53_zone.cluster would be deleted; aws_route53_zone is protected",
+            "aws_route53_zone.cluster would be deleted but has no KubernetesCluster tag; \
+             list aws_route53_zone in untagged_types if it cannot have one",
+            "aws_instance.other would be deleted but is not tagged KubernetesCluster = development1",
+            // a missing tag is no way around the rule
+            "aws_route53_record.api would be deleted but has no KubernetesCluster tag; \
+             list aws_route53_record in untagged_types if it cannot have one",
+        ]
+    );
+    let prod = ClusterContext::new(&conf, "production1").unwrap();
+    assert!(policy
+        .violations(&plan, &prod)
+        .last()
+        .unwrap()
+        .contains("the plan deletes 5 resources; production plans may delete at most 2"));
+    assert!(Policy::default().violations(&plan, &prod).is_empty());
+
+    // config.toml can only tighten the repo's limits
+    let mut repo = Policy {
+        protected_types: vec!["aws_s3_bucket".to_owned()],
+        cluster_tag: None,
+        untagged_types: vec!["aws_route53_record".to_owned()],
+        max_deletes: vec![("production".to_owned(), 1)].into_iter().collect(),
+    };
+    assert!(repo.merge(&conf.policy).is_empty());
+    assert_eq!(
+        repo.protected_types,
+        vec!["aws_s3_bucket", "aws_route53_zone"]
+    );
+    assert_eq!(repo.max_deletes["production"], 1);
+    assert_eq!(repo.cluster_tag.as_deref(), Some("KubernetesCluster"));
+    assert_eq!(
+        repo.untagged_types,
+        vec!["aws_route53_record", "aws_iam_role"]
+    );
+}
+
+#[test]
+fn test_policy_config_cannot_loosen_tag_rule() {
+    let conf: Config = toml::from_str(&crate::config::test_config_toml(
+        r#"
+[policy]
+cluster_tag = "Owner"
+untagged_types = ["aws_instance"]
+"#,
+    ))
+    .unwrap();
+    let mut policy = Policy {
+        cluster_tag: Some("KubernetesCluster".to_owned()),
+        untagged_types: vec!["aws_route53_record".to_owned()],
+        ..Policy::default()
+    };
+    assert_eq!(
+        policy.merge(&conf.policy),
+        vec!["cluster_tag = \"Owner\"", "aws_instance in untagged_types",]
+    );
+    assert_eq!(policy.cluster_tag.as_deref(), Some("KubernetesCluster"));
+    assert_eq!(policy.untagged_types, vec!["aws_route53_record"]);
+
+    // the repo's tag rule still applies to what config.toml tried to exempt
+    let plan = PlanSummary::from_json(
+        r#"{"resource_changes": [
+    {"address": "aws_instance.other", "type": "aws_instance",
+     "change": {"actions": ["delete"], "before": {"tags": {"Owner": "development1", "KubernetesCluster": "development2"}}}}
+]}"#,
+    )
+    .unwrap();
+    let dev = ClusterContext::new(&conf, "development1").unwrap();
+    assert_eq!(
+        policy.violations(&plan, &dev),
+        vec!["aws_instance.other would be deleted but is not tagged KubernetesCluster = development1"]
+    );
+}
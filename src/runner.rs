@@ -1,19 +1,28 @@
 use anyhow::{anyhow, Error};
-use console::Style;
-use dialoguer::{theme::ColorfulTheme, Confirmation, Input, Select};
 use std::cell::{RefCell, RefMut};
 use std::collections::HashMap;
 use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, ExitStatus, Stdio};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
 use std::rc::Rc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
-pub struct Cmd<'a> {
-    pub command: Vec<&'a str>,
+pub struct Cmd {
+    pub command: Vec<String>,
     pub working_dir: Option<PathBuf>,
     pub env: Option<EnvVars>,
     pub writes_file: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+    /// Values that are shown as `***` wherever the command is displayed or
+    /// journaled, e.g. passwords passed as arguments.
+    pub sensitive: Vec<String>,
+    /// Written to the command's stdin, so that secrets stay out of its
+    /// arguments.
+    pub stdin: Option<String>,
 }
 
 type EnvVars = Rc<RefCell<HashMap<String, String>>>;
@@ -22,43 +31,120 @@ fn new_env_vars() -> EnvVars {
     Rc::new(RefCell::new(HashMap::new()))
 }
 
-impl<'a> Cmd<'a> {
-    pub fn new(command: Vec<&'a str>) -> Cmd {
+/// Replace every one of `values` in `s` with `***`.
+pub fn redact<S: AsRef<str>>(s: &str, values: &[S]) -> String {
+    let mut s = s.to_owned();
+    for value in values.iter().map(AsRef::as_ref).filter(|v| !v.is_empty()) {
+        s = s.replace(value, "***");
+    }
+    s
+}
+
+impl Cmd {
+    pub fn new<S: Into<String>>(command: Vec<S>) -> Cmd {
         Cmd {
-            command: command,
+            command: command.into_iter().map(Into::into).collect(),
             working_dir: None,
             env: Some(new_env_vars()),
             writes_file: None,
+            timeout: None,
+            sensitive: vec![],
+            stdin: None,
         }
     }
 
-    pub fn env(&'a mut self, var: &'a str, value: &'a str) -> &'a mut Cmd {
-        match &self.env {
-            Some(e) => {
-                let mut e = e.borrow_mut();
-                e.insert(var.to_string(), value.to_string());
-            }
-            None => self.env = Some(new_env_vars()),
-        }
+    pub fn env(&mut self, var: &str, value: &str) -> &mut Cmd {
+        let e = self.env.get_or_insert_with(new_env_vars);
+        e.borrow_mut().insert(var.to_string(), value.to_string());
         self
     }
 
-    pub fn dir(&'a mut self, path: PathBuf) -> &'a mut Cmd {
+    pub fn dir(&mut self, path: PathBuf) -> &mut Cmd {
         self.working_dir = Some(path);
         self
     }
 
-    pub fn writes_file(&'a mut self, path: PathBuf) -> &'a mut Cmd {
+    pub fn writes_file(&mut self, path: PathBuf) -> &mut Cmd {
         self.writes_file = Some(path);
         self
     }
 
-    pub fn spawn(&self) -> Result<Child, Error> {
-        if self.command.len() < 1 {
+    /// Kill the command, and everything it started, if it runs longer than
+    /// `timeout`.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Cmd {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Feed `data` to the command's stdin. Sensitive values in it are masked
+    /// when the command is shown.
+    pub fn stdin(&mut self, data: &str) -> &mut Cmd {
+        self.stdin = Some(data.to_owned());
+        self
+    }
+
+    /// Mask `value` wherever this command is shown.
+    pub fn sensitive(&mut self, value: &str) -> &mut Cmd {
+        if !value.is_empty() && !self.sensitive.iter().any(|v| v == value) {
+            self.sensitive.push(value.to_owned());
+        }
+        self
+    }
+
+    /// Replace every sensitive value in `s` with `***`.
+    pub fn redact(&self, s: &str) -> String {
+        redact(s, &self.sensitive)
+    }
+
+    /// The arguments as they may be shown or recorded.
+    pub fn argv(&self) -> Vec<String> {
+        self.command.iter().map(|arg| self.redact(arg)).collect()
+    }
+
+    /// The command as it would be typed in a shell, used to key answers.
+    /// Sensitive values are masked.
+    pub fn command_line(&self) -> String {
+        self.argv().join(" ")
+    }
+
+    /// Everything an operator needs to know before running this command: the
+    /// working directory, the arguments, extra environment variables, and the
+    /// file that stdout is written to.
+    pub fn describe(&self) -> String {
+        let cwd = match &self.working_dir {
+            Some(dir) => dir.clone(),
+            None => std::env::current_dir().unwrap_or_default(),
+        };
+        let mut lines = vec![
+            format!("PATH: {:?}", cwd),
+            format!("COMMAND: {:?}", self.argv()),
+        ];
+        if let Some(env_vars) = &self.env {
+            let env_vars = env_vars.borrow();
+            let mut keys: Vec<&String> = env_vars.keys().collect();
+            keys.sort();
+            for k in keys {
+                lines.push(format!("ENV: {}={}", k, self.redact(&env_vars[k])));
+            }
+        }
+        if let Some(data) = &self.stdin {
+            lines.push(format!("STDIN: {:?}", self.redact(data)));
+        }
+        if let Some(path) = &self.writes_file {
+            lines.push(format!("WRITES: {:?}", path));
+        }
+        if let Some(timeout) = self.timeout {
+            lines.push(format!("TIMEOUT: {}s", timeout.as_secs()));
+        }
+        lines.join("\n")
+    }
+
+    fn command(&self) -> Result<Command, Error> {
+        if self.command.is_empty() {
             return Err(anyhow!("invalid command"));
         }
 
-        let mut c = Command::new(self.command[0]);
+        let mut c = Command::new(&self.command[0]);
         if self.command.len() > 1 {
             for arg in self.command[1..].iter() {
                 c.arg(arg);
@@ -70,122 +156,360 @@ impl<'a> Cmd<'a> {
             }
         }
         if let Some(cwd) = &self.working_dir {
-            c.current_dir(&cwd);
+            c.current_dir(cwd);
+        }
+        if self.timeout.is_some() {
+            // A process group of its own, so that a timeout can kill
+            // everything the command started.
+            c.process_group(0);
+        }
+        if self.stdin.is_some() {
+            c.stdin(Stdio::piped());
         }
+        Ok(c)
+    }
+
+    /// Start the command, writing its stdin from a thread so that a command
+    /// that does not read it all cannot block us.
+    fn start(&self, c: &mut Command) -> Result<Child, Error> {
+        let mut child = c.spawn()?;
+        if let (Some(data), Some(mut stdin)) = (self.stdin.clone(), child.stdin.take()) {
+            thread::spawn(move || stdin.write_all(data.as_bytes()));
+        }
+        Ok(child)
+    }
+
+    /// Wait for `child` to exit. Past the timeout, its process group is sent
+    /// SIGTERM, then SIGKILL if it has not exited a few seconds later.
+    /// Returns the exit status and whether the command timed out.
+    fn wait(&self, child: &mut Child) -> Result<(ExitStatus, bool), Error> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Ok((child.wait()?, false)),
+        };
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if let Some(status) = child.try_wait()? {
+                return Ok((status, false));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let group = -(child.id() as libc::pid_t);
+        unsafe { libc::kill(group, libc::SIGTERM) };
+        let grace = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < grace {
+            if let Some(status) = child.try_wait()? {
+                return Ok((status, true));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        unsafe { libc::kill(group, libc::SIGKILL) };
+        Ok((child.wait()?, true))
+    }
 
-        if let Some(_) = &self.writes_file {
+    pub fn spawn(&self) -> Result<Child, Error> {
+        let mut c = self.command()?;
+        if self.writes_file.is_some() {
             // we only handle stdout redirection right now
             c.stdout(Stdio::piped());
         }
 
-        Ok(c.spawn()?)
+        self.start(&mut c)
+    }
+
+    /// Run the command quietly and collect its output. Fails if the command
+    /// times out.
+    pub fn output(&self) -> Result<Output, Error> {
+        let mut c = self.command()?;
+        c.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = self.start(&mut c)?;
+        let stdout = child
+            .stdout
+            .take()
+            .map(|out| tee(out, Box::new(io::sink())));
+        let stderr = child
+            .stderr
+            .take()
+            .map(|err| tee(err, Box::new(io::sink())));
+        let (status, timed_out) = self.wait(&mut child)?;
+        if timed_out {
+            return Err(anyhow!(
+                "{:?} timed out after {}s",
+                self.command_line(),
+                self.timeout.unwrap_or_default().as_secs()
+            ));
+        }
+        let collect = |reader: Option<JoinHandle<io::Result<Vec<u8>>>>| match reader {
+            Some(r) => r
+                .join()
+                .map_err(|_| anyhow!("output reader panicked"))?
+                .map_err(Error::from),
+            None => Ok(vec![]),
+        };
+        Ok(Output {
+            status,
+            stdout: collect(stdout)?,
+            stderr: collect(stderr)?,
+        })
+    }
+
+    /// Run the command to completion. With `capture`, stdout and stderr are
+    /// teed: printed to the terminal as they arrive, and also collected into
+    /// the returned `Outcome`. If the command writes a file, its stdout goes
+    /// to that file instead of the terminal.
+    pub fn run(&self, capture: bool) -> Result<Outcome, Error> {
+        let mut c = self.command()?;
+        if capture || self.writes_file.is_some() {
+            c.stdout(Stdio::piped());
+        }
+        if capture {
+            c.stderr(Stdio::piped());
+        }
+        let mut child = self.start(&mut c)?;
+
+        let stdout = match child.stdout.take() {
+            Some(out) => {
+                let sink: Box<dyn Write + Send> = match &self.writes_file {
+                    Some(path) => Box::new(File::create(path)?),
+                    None => Box::new(io::stdout()),
+                };
+                Some(tee(out, sink))
+            }
+            None => None,
+        };
+        let stderr = child
+            .stderr
+            .take()
+            .map(|err| tee(err, Box::new(io::stderr())));
+
+        let (status, timed_out) = self.wait(&mut child)?;
+        let mut captured = vec![];
+        for reader in [stdout, stderr] {
+            let bytes = match reader {
+                Some(reader) => reader
+                    .join()
+                    .map_err(|_| anyhow!("output reader panicked"))??,
+                None => vec![],
+            };
+            captured.push(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        let stderr = captured.pop().unwrap_or_default();
+        let mut output = captured.pop().unwrap_or_default();
+        output.push_str(&stderr);
+        if !capture {
+            output.clear();
+        }
+
+        Ok(Outcome {
+            status,
+            output,
+            stderr,
+            timed_out,
+        })
+    }
+}
+
+/// Copy everything from `src` to `sink`, returning a copy of what was read.
+fn tee<R: Read + Send + 'static>(
+    mut src: R,
+    mut sink: Box<dyn Write + Send>,
+) -> JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = src.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            sink.write_all(&buf[..n])?;
+            sink.flush()?;
+            captured.extend_from_slice(&buf[..n]);
+        }
+        Ok(captured)
+    })
+}
+
+/// Runs commands on behalf of a session. Every command a workflow issues goes
+/// through an executor, so that tests can script what commands do instead of
+/// running them.
+pub trait CommandExecutor {
+    /// Run `cmd` to completion, as `Cmd::run` does.
+    fn run(&self, cmd: &Cmd, capture: bool) -> Result<Outcome, Error>;
+    /// Run `cmd` quietly and collect its output, as `Cmd::output` does.
+    fn output(&self, cmd: &Cmd) -> Result<Output, Error>;
+}
+
+/// Runs commands for real.
+pub struct SystemExecutor;
+
+impl CommandExecutor for SystemExecutor {
+    fn run(&self, cmd: &Cmd, capture: bool) -> Result<Outcome, Error> {
+        cmd.run(capture)
+    }
+
+    fn output(&self, cmd: &Cmd) -> Result<Output, Error> {
+        cmd.output()
     }
 }
 
+/// The result of `Cmd::run`. `output` holds stdout followed by stderr, and is
+/// empty unless output was captured.
+pub struct Outcome {
+    pub status: ExitStatus,
+    pub output: String,
+    /// What the command printed on stderr alone, if output was captured.
+    pub stderr: String,
+    /// The command was killed for running past its timeout.
+    pub timed_out: bool,
+}
+
 pub enum Proc {
     Child(Child),
     Status(ExitStatus),
 }
 
-pub enum Expect<'a> {
+/// How often to run a command again when it does not behave as expected, and
+/// which failures are worth another attempt.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// Total number of attempts, including the first.
+    pub attempts: u32,
+    /// How long to wait before the second attempt.
+    pub backoff: Duration,
+    /// Each wait after that is this many times longer than the one before.
+    pub multiplier: u32,
+    /// No wait is longer than this, however many attempts there are.
+    pub max_backoff: Duration,
+    /// Exit codes worth retrying.
+    pub codes: Vec<i32>,
+    /// Output worth retrying, e.g. "connection refused".
+    pub output: Vec<String>,
+}
+
+impl Retry {
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        Retry {
+            attempts,
+            backoff,
+            multiplier: 2,
+            max_backoff: Duration::from_secs(300),
+            codes: vec![],
+            output: vec![],
+        }
+    }
+
+    /// Whether the output must be captured to decide on a retry.
+    pub fn inspects_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Whether a failed attempt is worth retrying. Without any codes or
+    /// output patterns, every failure is.
+    pub fn retryable(&self, status: &ExitStatus, output: &str) -> bool {
+        if self.codes.is_empty() && self.output.is_empty() {
+            return true;
+        }
+        status.code().is_some_and(|c| self.codes.contains(&c))
+            || self.output.iter().any(|pat| output.contains(pat.as_str()))
+    }
+
+    /// How long to wait after `attempt` attempts have been made.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.multiplier
+            .checked_pow(attempt.saturating_sub(1))
+            .and_then(|factor| self.backoff.checked_mul(factor))
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+}
+
+impl std::fmt::Display for Retry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "up to {} attempts, {}s apart at first",
+            self.attempts,
+            self.backoff.as_secs()
+        )?;
+        if !self.codes.is_empty() {
+            write!(f, ", on exit codes {:?}", self.codes)?;
+        }
+        if !self.output.is_empty() {
+            write!(f, ", on output containing {:?}", self.output)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a step's command is expected to do.
+#[derive(Debug, Clone)]
+pub enum Expect {
     Code(i32),
-    Output(&'a str),
-    CodeWithOutput((i32, &'a str)),
-    SuccessWithOutput(&'a str),
-    FailureWithOutput(&'a str),
+    /// Any of these exit codes, e.g. 0 or 2 from `terraform plan -detailed-exitcode`.
+    Codes(Vec<i32>),
+    Output(String),
+    CodeWithOutput((i32, String)),
+    SuccessWithOutput(String),
+    FailureWithOutput(String),
     Success,
     Failure,
     Any,
 }
 
-#[macro_export]
-macro_rules! prompt_run {
-    // prompt; cmd->Proc; Expect; on failure say
-    ($prompt:literal, $cmd:expr, $expect:expr) => {{
-        use crate::runner::{Expect, Proc};
-        use std::env::current_dir;
-        use std::io::Write;
-        use std::process::Child;
-
-        println!("---");
-        // print path
-        println!(
-            "PATH: {:?}",
-            $cmd.working_dir.as_ref().unwrap_or(&current_dir()?)
-        );
-        // print command
-        println!("COMMAND: {:?}", $cmd.command);
-        // print choice prompt
-        let theme = prompt_theme();
-        let idx = Select::with_theme(&theme)
-            .with_prompt($prompt)
-            .items(&["execute", "exit", "skip"])
-            .interact()
-            .unwrap();
-
-        match idx {
-            2 => { /* skip */ }
-            1 => std::process::exit(1),
-            0 => {
-                // spawn - get child
-                let mut writes_file = false;
-                if let Some(_) = $cmd.writes_file {
-                    writes_file = true;
-                }
-
-                let status = {
-                    match writes_file {
-                        false => {
-                            let mut child = $cmd.spawn()?;
-                            child.wait()?
-                        }
-                        true => {
-                            let path = $cmd.writes_file.as_ref().unwrap();
-                            let child = $cmd.spawn()?;
-                            let output = child.wait_with_output()?;
-                            let mut f = File::create(&path)?;
-                            f.write_all(&output.stdout)?;
-                            output.status
-                        }
-                    }
-                };
-                // Assign a bool based on the result we expect
-                let ok = {
-                    match $expect {
-                        Expect::Success => status.success(),
-                        Expect::Failure => !status.success(),
-                        Expect::Code(code) => status.code().unwrap() == code,
-                        Expect::Any => true,
-                        // Expect variants that say we should scan the command
-                        // output are unimplemented
-                        _ => unimplemented!(),
-                    }
-                };
+impl Expect {
+    /// Whether the command's output must be captured to check this expectation.
+    pub fn inspects_output(&self) -> bool {
+        matches!(
+            self,
+            Expect::Output(_)
+                | Expect::CodeWithOutput(_)
+                | Expect::SuccessWithOutput(_)
+                | Expect::FailureWithOutput(_)
+        )
+    }
 
-                if !ok {
-                    let idx = Select::with_theme(&theme)
-                        .with_prompt("Previous command behaved unexpectedly. Proceed with caution.")
-                        .items(&["continue", "exit"])
-                        .interact()
-                        .unwrap();
-                    if idx == 1 {
-                        std::process::exit(1)
-                    }
-                }
+    /// Check a finished command against this expectation. Output patterns
+    /// match if they appear anywhere in stdout or stderr.
+    pub fn matches(&self, status: &ExitStatus, output: &str) -> bool {
+        match self {
+            Expect::Code(code) => status.code() == Some(*code),
+            Expect::Codes(codes) => status.code().is_some_and(|c| codes.contains(&c)),
+            Expect::Output(pat) => output.contains(pat.as_str()),
+            Expect::CodeWithOutput((code, pat)) => {
+                status.code() == Some(*code) && output.contains(pat.as_str())
             }
-            // we know only 0, 1, 2 are reachable
-            _ => unreachable!(),
+            Expect::SuccessWithOutput(pat) => status.success() && output.contains(pat.as_str()),
+            Expect::FailureWithOutput(pat) => !status.success() && output.contains(pat.as_str()),
+            Expect::Success => status.success(),
+            Expect::Failure => !status.success(),
+            Expect::Any => true,
         }
-    }};
+    }
 }
 
-fn prompt_theme() -> ColorfulTheme {
-    ColorfulTheme {
-        values_style: Style::new().yellow().dim(),
-        indicator_style: Style::new().yellow().bold(),
-        yes_style: Style::new().yellow().dim(),
-        no_style: Style::new().yellow().dim(),
-        ..ColorfulTheme::default()
+impl std::fmt::Display for Expect {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Expect::Code(code) => write!(f, "exit code {}", code),
+            Expect::Codes(codes) => {
+                let codes: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
+                write!(f, "exit code {}", codes.join(" or "))
+            }
+            Expect::Output(pat) => write!(f, "output containing {:?}", pat),
+            Expect::CodeWithOutput((code, pat)) => {
+                write!(f, "exit code {} with output containing {:?}", code, pat)
+            }
+            Expect::SuccessWithOutput(pat) => {
+                write!(f, "success with output containing {:?}", pat)
+            }
+            Expect::FailureWithOutput(pat) => {
+                write!(f, "failure with output containing {:?}", pat)
+            }
+            Expect::Success => write!(f, "success"),
+            Expect::Failure => write!(f, "failure"),
+            Expect::Any => write!(f, "any result"),
+        }
     }
 }
 
@@ -204,5 +528,121 @@ fn test_cmd() {
     );
 }
 
+#[test]
+fn test_describe() {
+    let mut c = Cmd::new(vec!["helm", "template", "charts/pp-argo-cd"]);
+    c.dir(PathBuf::from("/deployments"))
+        .env("AWS_PROFILE", "infra")
+        .writes_file(PathBuf::from("/tmp/argo_template.yaml"));
+    assert_eq!(
+        c.describe(),
+        r#"PATH: "/deployments"
+COMMAND: ["helm", "template", "charts/pp-argo-cd"]
+ENV: AWS_PROFILE=infra
+WRITES: "/tmp/argo_template.yaml""#
+    );
+}
+
+#[test]
+fn test_redact() {
+    let mut c = Cmd::new(vec![
+        "kubectl",
+        "patch",
+        "secret",
+        "argocd-secret",
+        "--patch",
+        r#"{ "data": { "dex.github.clientSecret": "c2VjcmV0"}}"#,
+    ]);
+    c.env("DEX_SECRET", "c2VjcmV0").sensitive("c2VjcmV0");
+    assert_eq!(
+        c.command_line(),
+        r#"kubectl patch secret argocd-secret --patch { "data": { "dex.github.clientSecret": "***"}}"#
+    );
+    assert!(!c.describe().contains("c2VjcmV0"));
+    // the command itself still gets the value
+    assert!(c.command[5].contains("c2VjcmV0"));
+}
+
+#[test]
+fn test_stdin() {
+    let mut c = Cmd::new(vec!["cat"]);
+    c.stdin("c2VjcmV0\n").sensitive("c2VjcmV0");
+    let output = c.output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "c2VjcmV0\n");
+    assert!(c.describe().contains(r#"STDIN: "***\n""#));
+}
+
+#[test]
+fn test_run_captures_output() {
+    let c = Cmd::new(vec![
+        "sh",
+        "-c",
+        "echo to-stdout; echo to-stderr >&2; exit 3",
+    ]);
+    let outcome = c.run(true).unwrap();
+    assert_eq!(outcome.status.code(), Some(3));
+    assert!(outcome.output.contains("to-stdout"));
+    assert!(outcome.output.contains("to-stderr"));
+    assert_eq!(outcome.stderr, "to-stderr\n");
+}
+
+#[test]
+fn test_expect_output() {
+    let c = Cmd::new(vec![
+        "sh",
+        "-c",
+        "echo 'Error from server (AlreadyExists)' >&2; exit 1",
+    ]);
+    let outcome = c.run(true).unwrap();
+    let (status, output) = (&outcome.status, outcome.output.as_str());
+
+    let pat = || "AlreadyExists".to_owned();
+    assert!(Expect::FailureWithOutput(pat()).matches(status, output));
+    assert!(Expect::CodeWithOutput((1, pat())).matches(status, output));
+    assert!(Expect::Output(pat()).matches(status, output));
+    assert!(!Expect::SuccessWithOutput(pat()).matches(status, output));
+    assert!(!Expect::FailureWithOutput("NotFound".to_owned()).matches(status, output));
+    assert!(!Expect::CodeWithOutput((2, pat())).matches(status, output));
+    assert!(Expect::Codes(vec![0, 1]).matches(status, output));
+    assert!(!Expect::Codes(vec![0, 2]).matches(status, output));
+}
+
+#[test]
+fn test_retry_policy() {
+    let c = Cmd::new(vec!["sh", "-c", "echo 'connection refused' >&2; exit 20"]);
+    let outcome = c.run(true).unwrap();
+    let (status, output) = (&outcome.status, outcome.output.as_str());
+
+    let mut retry = Retry::new(4, Duration::from_secs(5));
+    assert!(retry.retryable(status, output));
+    retry.codes = vec![1];
+    assert!(!retry.retryable(status, output));
+    retry.output = vec!["connection refused".to_owned()];
+    assert!(retry.retryable(status, output));
+
+    assert_eq!(retry.delay(1), Duration::from_secs(5));
+    assert_eq!(retry.delay(3), Duration::from_secs(20));
+    // long retry policies wait at most max_backoff
+    assert_eq!(retry.delay(7), Duration::from_secs(300));
+    assert_eq!(retry.delay(40), Duration::from_secs(300));
+    assert_eq!(retry.delay(u32::MAX), Duration::from_secs(300));
+    retry.multiplier = u32::MAX;
+    assert_eq!(retry.delay(2), Duration::from_secs(300));
+}
+
+#[test]
+fn test_timeout_kills_process_group() {
+    let mut c = Cmd::new(vec!["sh", "-c", "sleep 30 & sleep 30; echo done"]);
+    c.timeout(Duration::from_millis(300));
+    let started = Instant::now();
+    let outcome = c.run(true).unwrap();
+    // the output readers only finish once the background sleep is gone too
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert!(outcome.timed_out);
+    assert!(!outcome.status.success());
+    assert!(!outcome.output.contains("done"));
+    assert!(c.output().is_err());
+}
+
 // Memory safety: while something is borrowed, it can no longer be moved
 // or changed, or mutably borrowed again.
@@ -0,0 +1,563 @@
+use crate::checkpoint::{Checkpoint, StepStatus};
+use crate::journal::{Entry, Journal};
+use crate::runner::{Cmd, CommandExecutor, Outcome, SystemExecutor};
+use crate::wait::Until;
+use anyhow::{anyhow, Context, Error};
+use chrono::Utc;
+use console::Style;
+use dialoguer::{theme::ColorfulTheme, Editor, Select};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{stdin, IsTerminal};
+use std::path::Path;
+use std::rc::Rc;
+
+/// What to do with a step that clusterctl is about to run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Choice {
+    Execute,
+    Skip,
+    Exit,
+}
+
+/// What to do after a step failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// Run the step again.
+    Retry,
+    /// Go on as if the step had succeeded.
+    Continue,
+    /// Go on without the step.
+    Skip,
+    Exit,
+}
+
+/// Pre-recorded answers for running clusterctl unattended.
+///
+/// Steps are keyed by their command line (as shown after `COMMAND:`) or by
+/// their prompt text. Steps without an entry get `default`, if set.
+///
+/// ```toml
+/// cluster_id = "development1"
+/// default = "execute"
+///
+/// [steps]
+/// "kubectl create ns mars" = "skip"
+/// "Open buffer in your $EDITOR to input the secret?" = "skip"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Answers {
+    pub cluster_id: Option<String>,
+    pub default: Option<Choice>,
+    #[serde(default)]
+    pub steps: HashMap<String, Choice>,
+}
+
+impl Answers {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let s = std::fs::read_to_string(&path).context("answers file not found")?;
+        toml::from_str(&s).context("answers file parsing error")
+    }
+
+    fn lookup(&self, keys: &[&str]) -> Option<Choice> {
+        keys.iter()
+            .find_map(|k| self.steps.get(*k).copied())
+            .or(self.default)
+    }
+}
+
+/// Answers every question clusterctl asks, either by prompting the operator
+/// or from `--yes` and `--answers`, and runs the commands that were agreed to.
+///
+/// In a dry run every step is accepted and printed, but nothing is executed.
+/// Otherwise every step is recorded in the run journal and the workflow's
+/// checkpoint, once a workflow is started.
+pub struct Session {
+    yes: bool,
+    answers: Option<Answers>,
+    interactive: bool,
+    dry_run: bool,
+    resume: bool,
+    /// Apply terraform plans that break policy.
+    policy_override: bool,
+    /// Policy violations overridden since the last journal entry.
+    overrides: RefCell<Vec<String>>,
+    journal: RefCell<Option<Journal>>,
+    progress: RefCell<Option<Progress>>,
+    theme: ColorfulTheme,
+    executor: Rc<dyn CommandExecutor>,
+}
+
+/// A step offered to the operator: its name, prompt and command line.
+type OfferedStep = (String, String, String);
+
+/// Where the current workflow is, for checkpointing.
+struct Progress {
+    checkpoint: Checkpoint,
+    /// The step most recently offered, until it is finished or skipped.
+    offered: Option<OfferedStep>,
+    /// Steps passed over because a previous attempt ran them.
+    passed: Vec<String>,
+    /// Still passing over steps run by a previous attempt.
+    resuming: bool,
+}
+
+impl Progress {
+    /// Offer a step, returning its status from a previous attempt if we are
+    /// resuming past it. Only steps that succeeded are passed over; one the
+    /// operator skipped is offered again.
+    fn offer(
+        &mut self,
+        step: &str,
+        prompt: &str,
+        command: &str,
+    ) -> Result<Option<StepStatus>, Error> {
+        self.offered = Some((step.to_owned(), prompt.to_owned(), command.to_owned()));
+        if self.resuming {
+            if let Some(StepStatus::Succeeded) = self.checkpoint.status(step, command) {
+                self.passed.push(step.to_owned());
+                return Ok(Some(StepStatus::Succeeded));
+            }
+            self.resuming = false;
+            self.checkpoint.keep(&self.passed)?;
+            println!("Resuming from this step");
+        }
+        Ok(None)
+    }
+
+    /// Mark the offered step finished.
+    fn finish(&mut self, status: StepStatus) -> Result<(), Error> {
+        if let Some((step, prompt, command)) = self.offered.take() {
+            self.checkpoint.mark(&step, &prompt, &command, status)?;
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    pub fn new(
+        yes: bool,
+        answers: Option<Answers>,
+        dry_run: bool,
+        resume: bool,
+    ) -> Result<Self, Error> {
+        let interactive = stdin().is_terminal();
+        if !interactive && !yes && answers.is_none() && !dry_run {
+            return Err(anyhow!(
+                "stdin is not a terminal; pass --yes or --answers to run non-interactively"
+            ));
+        }
+        Ok(Session {
+            yes,
+            answers,
+            interactive,
+            dry_run,
+            resume,
+            policy_override: false,
+            overrides: RefCell::new(vec![]),
+            journal: RefCell::new(None),
+            progress: RefCell::new(None),
+            theme: prompt_theme(),
+            executor: Rc::new(SystemExecutor),
+        })
+    }
+
+    /// Run commands with `executor` rather than for real.
+    #[cfg(test)]
+    pub fn with_executor(mut self, executor: Rc<dyn CommandExecutor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Apply terraform plans that break policy, as with `--override-policy`.
+    pub fn with_policy_override(mut self, policy_override: bool) -> Self {
+        self.policy_override = policy_override;
+        self
+    }
+
+    /// Whether a plan that breaks policy in the ways listed may be applied. If
+    /// so, the violations are journaled with the next step recorded.
+    pub fn override_policy(&self, violations: &[String]) -> bool {
+        if self.policy_override {
+            self.overrides.borrow_mut().extend_from_slice(violations);
+        }
+        self.policy_override
+    }
+
+    /// Start journaling and checkpointing a workflow. With `--resume`, steps
+    /// that succeeded in the previous attempt are listed and then passed over,
+    /// and those it skipped are listed to be offered again. A
+    /// workflow that hands off to another keeps the journal and checkpoint
+    /// that are already open.
+    pub fn start_workflow<P: AsRef<Path>>(
+        &self,
+        assets_cache_path: P,
+        cluster_id: &str,
+        workflow: &str,
+    ) -> Result<(), Error> {
+        if self.dry_run || self.journal.borrow().is_some() {
+            return Ok(());
+        }
+        let assets_cache_path = assets_cache_path.as_ref();
+        let journal = Journal::create(assets_cache_path, cluster_id, workflow)?;
+        println!(
+            "Recording this run as {} in {}\n",
+            journal.id,
+            journal.path.display()
+        );
+        *self.journal.borrow_mut() = Some(journal);
+
+        let checkpoint = if self.resume {
+            Checkpoint::load(assets_cache_path, cluster_id, workflow)?
+        } else {
+            Checkpoint::new(assets_cache_path, cluster_id, workflow)
+        };
+        if self.resume {
+            if checkpoint.steps.is_empty() {
+                println!("No previous attempt of {} on {}\n", workflow, cluster_id);
+            }
+            let (succeeded, skipped): (Vec<_>, Vec<_>) = checkpoint
+                .steps
+                .iter()
+                .partition(|s| s.status == StepStatus::Succeeded);
+            if !succeeded.is_empty() {
+                println!("Steps that already succeeded:");
+                for step in succeeded {
+                    println!("  {}", step.step);
+                }
+                println!();
+            }
+            if !skipped.is_empty() {
+                println!("Steps skipped by the previous attempt, to be offered again:");
+                for step in skipped {
+                    println!("  {}", step.step);
+                }
+                println!();
+            }
+        }
+        *self.progress.borrow_mut() = Some(Progress {
+            checkpoint,
+            offered: None,
+            passed: vec![],
+            resuming: self.resume,
+        });
+        Ok(())
+    }
+
+    /// Offer a step of the workflow for checkpointing. Returns its status if
+    /// a previous attempt already ran it and we are resuming past it.
+    pub fn offer(
+        &self,
+        step: &str,
+        prompt: &str,
+        command_line: &str,
+    ) -> Result<Option<StepStatus>, Error> {
+        match self.progress.borrow_mut().as_mut() {
+            Some(progress) => progress.offer(step, prompt, command_line),
+            None => Ok(None),
+        }
+    }
+
+    /// Mark the step last offered as succeeded.
+    pub fn finish_step(&self) -> Result<(), Error> {
+        if let Some(progress) = self.progress.borrow_mut().as_mut() {
+            progress.finish(StepStatus::Succeeded)?;
+        }
+        Ok(())
+    }
+
+    /// Record that the operator chose not to execute a step.
+    pub fn record_choice(&self, prompt: &str, cmd: &Cmd, choice: Choice) -> Result<(), Error> {
+        if choice == Choice::Skip {
+            if let Some(progress) = self.progress.borrow_mut().as_mut() {
+                progress.finish(StepStatus::Skipped)?;
+            }
+        }
+        self.record(Entry::new(prompt, cmd, choice, Utc::now()))
+    }
+
+    fn record(&self, mut entry: Entry) -> Result<(), Error> {
+        entry.policy_overrides = self.overrides.take();
+        if let Some(journal) = self.journal.borrow_mut().as_mut() {
+            journal.record(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// Whether an operator is at the terminal to answer prompts.
+    pub fn is_interactive(&self) -> bool {
+        self.interactive && !self.dry_run
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Run a command, teeing its output if `capture` is set, and journal it
+    /// under `prompt`. Returns `None` in a dry run, when nothing was executed.
+    pub fn run(&self, prompt: &str, cmd: &Cmd, capture: bool) -> Result<Option<Outcome>, Error> {
+        if self.dry_run {
+            println!("(dry run: not executed)");
+            return Ok(None);
+        }
+        let started_at = Utc::now();
+        let result = self.executor.run(cmd, capture);
+        let mut entry = Entry::new(prompt, cmd, Choice::Execute, started_at);
+        match &result {
+            Ok(outcome) => entry.exit_code = outcome.status.code(),
+            Err(e) => entry.error = Some(e.to_string()),
+        }
+        self.record(entry)?;
+        Ok(Some(result?))
+    }
+
+    /// Run a read-only command quietly and return its trimmed stdout. In a dry
+    /// run the command is printed and `placeholder` is returned instead.
+    pub fn query(&self, cmd: &Cmd, placeholder: &str) -> Result<String, Error> {
+        if self.dry_run {
+            println!("---\n{}\n(dry run: not executed)", cmd.describe());
+            return Ok(placeholder.to_owned());
+        }
+        let output = self.executor.output(cmd)?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "{:?} failed: {}",
+                cmd.command_line(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Write a file that a later command reads. A dry run only reports it.
+    pub fn write_file<P: AsRef<Path>>(&self, path: P, contents: &str) -> Result<(), Error> {
+        if self.dry_run {
+            println!("---\nWRITES: {:?}\n(dry run: not written)", path.as_ref());
+            return Ok(());
+        }
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    /// Create a directory that a later command writes to. A dry run only
+    /// reports it.
+    pub fn create_dir<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        if self.dry_run {
+            println!("---\nCREATES: {:?}\n(dry run: not created)", path.as_ref());
+            return Ok(());
+        }
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Ask whether to execute, skip, or exit before running a command.
+    pub fn choose(&self, prompt: &str, cmd: &Cmd) -> Result<Choice, Error> {
+        if self.dry_run {
+            return Ok(Choice::Execute);
+        }
+        if let Some(choice) = self.answer(&[&cmd.command_line(), prompt]) {
+            println!("{} {:?}", prompt, choice);
+            return Ok(choice);
+        }
+        self.require_interactive(prompt)?;
+        let idx = Select::with_theme(&self.theme)
+            .with_prompt(prompt)
+            .items(&["execute", "exit", "skip"])
+            .interact()?;
+        Ok([Choice::Execute, Choice::Exit, Choice::Skip][idx])
+    }
+
+    /// Ask a yes/no question. An answer of "execute" means yes, "skip" means
+    /// no, and "exit" stops clusterctl.
+    pub fn confirm(&self, msg: &str) -> Result<bool, Error> {
+        self.yes_or_no(msg, msg)
+    }
+
+    /// Ask a yes/no question that `--yes` and the answers file's `default` do
+    /// not answer, e.g. whether to apply a plan that was flagged. Unattended
+    /// runs need an answer for `msg` itself under `[steps]`.
+    pub fn insist(&self, msg: &str) -> Result<bool, Error> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        let answer = self.answers.as_ref().and_then(|a| a.steps.get(msg));
+        if let Some(choice) = answer {
+            return Ok(self.take_answer(msg, *choice));
+        }
+        if !self.interactive {
+            return Err(anyhow!(
+                "prompt {:?} needs an operator, or an answer for it under [steps] in the answers file; --yes and default answers do not cover it",
+                msg
+            ));
+        }
+        self.ask_yes_or_no(msg)
+    }
+
+    fn yes_or_no(&self, msg: &str, command_line: &str) -> Result<bool, Error> {
+        if self.dry_run {
+            return Ok(true);
+        }
+        if let Some(choice) = self.answer(&[command_line, msg]) {
+            return Ok(self.take_answer(msg, choice));
+        }
+        self.require_interactive(msg)?;
+        self.ask_yes_or_no(msg)
+    }
+
+    /// Act on a recorded answer to a yes/no question.
+    fn take_answer(&self, msg: &str, choice: Choice) -> bool {
+        println!("{} {:?}", msg, choice);
+        if choice == Choice::Exit {
+            std::process::exit(1);
+        }
+        choice == Choice::Execute
+    }
+
+    fn ask_yes_or_no(&self, msg: &str) -> Result<bool, Error> {
+        let idx = Select::with_theme(&self.theme)
+            .with_prompt(msg)
+            .items(&["yes", "no"])
+            .interact()?;
+        Ok(idx == 0)
+    }
+
+    /// Ask the operator how to recover from a step that failed, offering
+    /// `options`. Unattended runs never go on past a failure.
+    pub fn recover(&self, msg: &str, options: &[Recovery]) -> Result<Recovery, Error> {
+        if !self.interactive {
+            return Ok(Recovery::Exit);
+        }
+        let items: Vec<String> = options
+            .iter()
+            .map(|o| format!("{:?}", o).to_lowercase())
+            .collect();
+        let idx = Select::with_theme(&self.theme)
+            .with_prompt(msg)
+            .items(&items)
+            .interact()?;
+        Ok(options[idx])
+    }
+
+    /// Check `cmd` until the condition `what` holds or `until` times out, and
+    /// return whether it held. Returns None in a dry run.
+    pub fn wait_until(&self, what: &str, until: &Until, cmd: &Cmd) -> Result<Option<bool>, Error> {
+        if self.dry_run {
+            println!(
+                "---\nWAIT UNTIL: {} (up to {}s)\n{}\n(dry run: not executed)",
+                what,
+                until.timeout.as_secs(),
+                cmd.describe()
+            );
+            return Ok(None);
+        }
+        let started_at = Utc::now();
+        let held = until.poll(what, cmd, self.executor.as_ref())?;
+        let prompt = format!("Wait until {}", what);
+        let mut entry = Entry::new(&prompt, cmd, Choice::Execute, started_at);
+        if !held {
+            entry.error = Some(format!("timed out after {}s", until.timeout.as_secs()));
+        }
+        self.record(entry)?;
+        Ok(Some(held))
+    }
+
+    /// Wait for the operator. Unattended runs print the message and move on.
+    pub fn pause(&self, msg: &str) -> Result<(), Error> {
+        if self.yes || self.answers.is_some() || self.dry_run {
+            println!("{}", msg);
+            return Ok(());
+        }
+        Select::with_theme(&self.theme)
+            .with_prompt(msg)
+            .items(&["I'm done waiting"])
+            .interact()?;
+        Ok(())
+    }
+
+    /// Open the operator's $EDITOR and return what they wrote.
+    pub fn edit(&self, msg: &str) -> Result<Option<String>, Error> {
+        if self.dry_run {
+            return Ok(Some("<secret from $EDITOR>".to_owned()));
+        }
+        self.require_interactive(msg)?;
+        Ok(Editor::new().edit(msg)?)
+    }
+
+    pub fn pick_cluster_id(&self, ids: &[&str]) -> Result<String, Error> {
+        if let Some(id) = self.answers.as_ref().and_then(|a| a.cluster_id.as_ref()) {
+            if !ids.contains(&id.as_str()) {
+                return Err(anyhow!("unknown cluster id {} in answers file", id));
+            }
+            println!("Selected cluster id {}", id);
+            return Ok(id.to_owned());
+        }
+        if !self.interactive {
+            return Err(anyhow!(
+                "no cluster id given; pass --cluster or set cluster_id in the answers file"
+            ));
+        }
+        let idx = Select::with_theme(&self.theme)
+            .with_prompt("Select a cluster id")
+            .items(ids)
+            .interact()?;
+        Ok(ids[idx].to_owned())
+    }
+
+    fn answer(&self, keys: &[&str]) -> Option<Choice> {
+        let answer = self.answers.as_ref().and_then(|a| a.lookup(keys));
+        match answer {
+            Some(choice) => Some(choice),
+            None if self.yes => Some(Choice::Execute),
+            None => None,
+        }
+    }
+
+    fn require_interactive(&self, prompt: &str) -> Result<(), Error> {
+        if self.interactive {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "no answer for prompt {:?} in non-interactive mode",
+                prompt
+            ))
+        }
+    }
+}
+
+fn prompt_theme() -> ColorfulTheme {
+    ColorfulTheme {
+        values_style: Style::new().yellow().dim(),
+        indicator_style: Style::new().yellow().bold(),
+        yes_style: Style::new().yellow().dim(),
+        no_style: Style::new().yellow().dim(),
+        ..ColorfulTheme::default()
+    }
+}
+
+#[test]
+fn test_answers_lookup() {
+    let answers: Answers = toml::from_str(
+        r#"
+cluster_id = "development1"
+default = "skip"
+
+[steps]
+"kubectl create ns mars" = "execute"
+"Deploy argocd?" = "exit"
+"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        answers.lookup(&["kubectl create ns mars", "Execute?"]),
+        Some(Choice::Execute)
+    );
+    assert_eq!(
+        answers.lookup(&["kubectl apply -f x.yaml", "Deploy argocd?"]),
+        Some(Choice::Exit)
+    );
+    assert_eq!(answers.lookup(&["helm dep update"]), Some(Choice::Skip));
+}
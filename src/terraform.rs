@@ -1,103 +1,550 @@
-use crate::runner::Proc;
-use anyhow::{anyhow, Error};
+use crate::runner::{Cmd, Outcome};
+use crate::session::Session;
+use anyhow::{anyhow, Context, Error};
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, Stdio};
 
-pub fn plan_destroy_with_tfvars_file<P: AsRef<Path>>(
-    dir: P,
-    tfvars: &str,
-    profile: &str,
-) -> Result<ExitStatus, Error> {
+fn terraform<P: AsRef<Path>>(dir: P, args: Vec<&str>, profile: Option<&str>) -> Cmd {
+    let mut command = vec!["terraform"];
+    command.extend(args);
+    let mut cmd = Cmd::new(command);
+    cmd.dir(dir.as_ref().to_path_buf());
+    if let Some(profile) = profile {
+        cmd.env("AWS_PROFILE", profile);
+    }
+    cmd
+}
+
+pub fn plan_destroy_with_tfvars_file<P: AsRef<Path>>(dir: P, tfvars: &str, profile: &str) -> Cmd {
     let tfvars = format!("{}.tfvars", tfvars);
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec![
-        "plan",
-        "-out",
-        "tfplan.out",
-        "-var-file",
-        &tfvars,
-        "-destroy",
-        "-detailed-exitcode",
-    ]);
-    Ok(cmd.status()?)
-}
-
-pub fn plan_with_tfvars_file<P: AsRef<Path>>(
-    dir: P,
-    tfvars: &str,
-    profile: &str,
-) -> Result<ExitStatus, Error> {
+    terraform(
+        dir,
+        vec![
+            "plan",
+            "-out",
+            "tfplan.out",
+            "-var-file",
+            &tfvars,
+            "-destroy",
+            "-detailed-exitcode",
+        ],
+        Some(profile),
+    )
+}
+
+pub fn plan_with_tfvars_file<P: AsRef<Path>>(dir: P, tfvars: &str, profile: &str) -> Cmd {
     let tfvars = format!("{}.tfvars", tfvars);
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec![
-        "plan",
-        "-out",
-        "tfplan.out",
-        "-var-file",
-        &tfvars,
-        "-detailed-exitcode",
-    ]);
-    Ok(cmd.status()?)
-}
-
-pub fn apply<P: AsRef<Path>>(dir: P, profile: &str) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec!["apply", "tfplan.out"]);
-    Ok(cmd.status()?)
-}
-
-pub fn workspace_select<P: AsRef<Path>>(
-    dir: P,
-    workspace: &str,
-    profile: &str,
-) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec!["workspace", "select", workspace]);
-    Ok(cmd.status()?)
-}
-
-pub fn workspace_show<P: AsRef<Path>>(dir: P, profile: &str) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec!["workspace", "show"]);
-    Ok(cmd.status()?)
-}
-
-pub fn version<P: AsRef<Path>>(dir: P, profile: &str) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec!["version"]);
-    Ok(cmd.status()?)
-}
-
-pub fn state_rm<P: AsRef<Path>>(
-    dir: P,
-    states: &[&str],
-    profile: &str,
-) -> Result<ExitStatus, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.env("AWS_PROFILE", profile);
-    cmd.current_dir(&dir);
-    cmd.args(vec!["state", "rm"]);
-    for state in states {
-        cmd.arg(state);
-    }
-    Ok(cmd.status()?)
-}
-
-pub fn get_update<P: AsRef<Path>>(dir: P) -> Result<Proc, Error> {
-    let mut cmd = Command::new("terraform");
-    cmd.current_dir(&dir);
-    cmd.args(vec!["get", "-update"]);
-    Ok(Proc::Status(cmd.status()?))
+    terraform(
+        dir,
+        vec![
+            "plan",
+            "-out",
+            "tfplan.out",
+            "-var-file",
+            &tfvars,
+            "-detailed-exitcode",
+        ],
+        Some(profile),
+    )
+}
+
+pub fn apply<P: AsRef<Path>>(dir: P, profile: &str) -> Cmd {
+    terraform(dir, vec!["apply", "tfplan.out"], Some(profile))
+}
+
+pub fn workspace_select<P: AsRef<Path>>(dir: P, workspace: &str, profile: &str) -> Cmd {
+    terraform(dir, vec!["workspace", "select", workspace], Some(profile))
+}
+
+pub fn workspace_show<P: AsRef<Path>>(dir: P, profile: &str) -> Cmd {
+    terraform(dir, vec!["workspace", "show"], Some(profile))
+}
+
+pub fn version<P: AsRef<Path>>(dir: P, profile: &str) -> Cmd {
+    terraform(dir, vec!["version"], Some(profile))
+}
+
+pub fn state_rm<P: AsRef<Path>>(dir: P, states: &[&str], profile: &str) -> Cmd {
+    let mut args = vec!["state", "rm"];
+    args.extend_from_slice(states);
+    terraform(dir, args, Some(profile))
+}
+
+pub fn get_update<P: AsRef<Path>>(dir: P) -> Cmd {
+    terraform(dir, vec!["get", "-update"], None)
+}
+
+/// The terraform subcommand `cmd` runs, e.g. `plan`, if it runs terraform.
+fn subcommand(cmd: &Cmd) -> Option<&str> {
+    match cmd.command.first().map(String::as_str) {
+        Some("terraform") => cmd.command.get(1).map(String::as_str),
+        _ => None,
+    }
+}
+
+/// Whether `cmd` is a `terraform apply`.
+pub fn is_apply(cmd: &Cmd) -> bool {
+    subcommand(cmd) == Some("apply")
+}
+
+/// Whether `cmd` reads or changes the state of the selected workspace.
+pub fn uses_workspace(cmd: &Cmd) -> bool {
+    matches!(
+        subcommand(cmd),
+        Some("plan") | Some("apply") | Some("state")
+    )
+}
+
+/// Whether `cmd` is a plan whose exit code says if it found changes.
+pub fn detailed_exitcode(cmd: &Cmd) -> bool {
+    subcommand(cmd) == Some("plan") && cmd.command.iter().any(|a| a == "-detailed-exitcode")
+}
+
+/// Another terraform command run in the same directory with the same
+/// environment as `cmd`.
+fn alongside(cmd: &Cmd, args: Vec<&str>) -> Cmd {
+    let mut command = vec!["terraform"];
+    command.extend(args);
+    let mut other = Cmd::new(command);
+    if let Some(dir) = &cmd.working_dir {
+        other.dir(dir.clone());
+    }
+    if let Some(env) = &cmd.env {
+        for (k, v) in env.borrow().iter() {
+            other.env(k, v);
+        }
+    }
+    other
+}
+
+/// `terraform show -json` for the plan file that `cmd` saves with `-out` or
+/// applies.
+pub fn show_json_for(cmd: &Cmd) -> Option<Cmd> {
+    let args = &cmd.command;
+    let plan = match subcommand(cmd)? {
+        "plan" => args.iter().skip_while(|a| *a != "-out").nth(1)?,
+        "apply" => args
+            .last()
+            .filter(|a| !a.starts_with('-') && *a != "apply")?,
+        _ => return None,
+    };
+    Some(alongside(cmd, vec!["show", "-json", plan]))
+}
+
+/// The workspace selected where `cmd` runs. A dry run assumes `expected`.
+pub fn workspace(session: &Session, cmd: &Cmd, expected: &str) -> Result<String, Error> {
+    session.query(&alongside(cmd, vec!["workspace", "show"]), expected)
+}
+
+/// Make sure `cmd` runs in the workspace `expected`. terraform output does not
+/// name the workspace, so nobody would notice a plan against the wrong one.
+pub fn check_workspace(session: &Session, cmd: &Cmd, expected: &str) -> Result<(), Error> {
+    let current = workspace(session, cmd, expected)?;
+    if session.dry_run() {
+        println!("would check workspace is {}", expected);
+        return Ok(());
+    }
+    if current != expected {
+        let dir = cmd.working_dir.as_deref().unwrap_or_else(|| Path::new("."));
+        return Err(anyhow!(
+            "terraform is on workspace {:?} in {}, not {:?}; refusing to run {}",
+            current,
+            dir.display(),
+            expected,
+            cmd.command_line()
+        ));
+    }
+    println!("\nOn terraform workspace {}", current);
+    Ok(())
+}
+
+/// The parts of `terraform show -json` that clusterctl looks at.
+#[derive(Debug, Deserialize)]
+struct PlanJson {
+    #[serde(default)]
+    resource_changes: Vec<ResourceChange>,
+}
+
+/// One resource in a plan and what the plan does to it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResourceChange {
+    pub address: String,
+    /// Absent for resources of the root module.
+    pub module_address: Option<String>,
+    #[serde(rename = "type")]
+    pub resource_type: String,
+    pub change: Change,
+}
+
+impl ResourceChange {
+    pub fn deletes(&self) -> bool {
+        self.change.actions.iter().any(|a| a == "delete")
+    }
+
+    /// The resource's tags before the plan, if it has any.
+    pub fn tags(&self) -> Option<BTreeMap<String, String>> {
+        let tags = self.change.before.as_ref()?.get("tags")?.as_object()?;
+        if tags.is_empty() {
+            return None;
+        }
+        let tags = tags
+            .iter()
+            .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_owned()))
+            .collect();
+        Some(tags)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Change {
+    /// Some of `create`, `read`, `update`, `delete` and `no-op`. A
+    /// replacement is both a delete and a create.
+    pub actions: Vec<String>,
+    /// The resource's attributes before the plan; null for a create.
+    #[serde(default)]
+    pub before: Option<serde_json::Value>,
+}
+
+/// How many resources a plan creates, updates and deletes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Counts {
+    pub create: usize,
+    pub update: usize,
+    pub delete: usize,
+}
+
+impl Counts {
+    fn add(&mut self, actions: &[String]) {
+        for action in actions {
+            match action.as_str() {
+                "create" => self.create += 1,
+                "update" => self.update += 1,
+                "delete" => self.delete += 1,
+                _ => {}
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Counts::default()
+    }
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} to create, {} to update, {} to delete",
+            self.create, self.update, self.delete
+        )
+    }
+}
+
+/// What a saved plan changes, counted per resource type and per module.
+#[derive(Debug, Default)]
+pub struct PlanSummary {
+    /// Resources the plan changes; no-ops and reads are left out.
+    pub changes: Vec<ResourceChange>,
+    pub total: Counts,
+    pub by_type: BTreeMap<String, Counts>,
+    /// The root module is `(root)`.
+    pub by_module: BTreeMap<String, Counts>,
+}
+
+impl PlanSummary {
+    /// Summarize the output of `terraform show -json`.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let plan: PlanJson =
+            serde_json::from_str(json).context("could not read the plan from terraform show")?;
+        let mut summary = PlanSummary::default();
+        for rc in plan.resource_changes {
+            let mut counts = Counts::default();
+            counts.add(&rc.change.actions);
+            if counts.is_empty() {
+                continue;
+            }
+            let module = rc.module_address.as_deref().unwrap_or("(root)");
+            summary.total.add(&rc.change.actions);
+            summary
+                .by_type
+                .entry(rc.resource_type.clone())
+                .or_default()
+                .add(&rc.change.actions);
+            summary
+                .by_module
+                .entry(module.to_owned())
+                .or_default()
+                .add(&rc.change.actions);
+            summary.changes.push(rc);
+        }
+        Ok(summary)
+    }
+}
+
+impl std::fmt::Display for PlanSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Plan: {}", self.total)?;
+        for (title, rows) in &[
+            ("module", &self.by_module),
+            ("resource type", &self.by_type),
+        ] {
+            if rows.is_empty() {
+                continue;
+            }
+            writeln!(f, "  by {}:", title)?;
+            for (name, c) in rows.iter() {
+                writeln!(
+                    f,
+                    "    {:<48} {:>4} create {:>4} update {:>4} delete",
+                    name, c.create, c.update, c.delete
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a `terraform plan` found, once its exit code has been checked against
+/// the plan it saved.
+#[derive(Debug)]
+pub enum PlanOutcome {
+    /// There is nothing to do.
+    NoChanges,
+    /// The saved plan changes something.
+    Changes(PlanSummary),
+    /// The plan failed, with what terraform printed on stderr.
+    Error(String),
+}
+
+impl PlanOutcome {
+    /// The outcome of a plan that exited with `code` and printed `stderr`,
+    /// given the plan it saved, if that could be read. With
+    /// `-detailed-exitcode` (`detailed`), 0 means no changes and 2 changes;
+    /// otherwise only the saved plan can tell. The saved plan has the final
+    /// say when terraform exits 2 for a -destroy plan with nothing left to
+    /// destroy, but a plan that exits 0 yet changes something is an error.
+    pub fn new(
+        code: Option<i32>,
+        detailed: bool,
+        stderr: &str,
+        plan: Option<PlanSummary>,
+    ) -> Result<Self, Error> {
+        let changed = match code {
+            Some(0) if detailed => Some(false),
+            Some(0) => None,
+            Some(2) if detailed => Some(true),
+            _ => return Ok(PlanOutcome::Error(stderr.trim().to_owned())),
+        };
+        let plan = match plan {
+            Some(plan) => plan,
+            None if changed == Some(false) => return Ok(PlanOutcome::NoChanges),
+            None => return Err(anyhow!("could not read the plan terraform saved")),
+        };
+        match (changed, plan.total.is_empty()) {
+            (Some(false), false) => Err(anyhow!(
+                "terraform plan exited 0, which means no changes, but its plan has {}",
+                plan.total
+            )),
+            (_, true) => Ok(PlanOutcome::NoChanges),
+            (_, false) => Ok(PlanOutcome::Changes(plan)),
+        }
+    }
+}
+
+/// The plan that `cmd` saves or applies, read back with `terraform show -json`.
+/// None in a dry run, when there is no plan to read.
+pub fn saved_plan(session: &Session, cmd: &Cmd) -> Result<Option<PlanSummary>, Error> {
+    let show = show_json_for(cmd)
+        .ok_or_else(|| anyhow!("{:?} does not save or apply a plan", cmd.command_line()))?;
+    let json = session.query(&show, "")?;
+    if session.dry_run() {
+        return Ok(None);
+    }
+    Ok(Some(PlanSummary::from_json(&json)?))
+}
+
+/// What the plan `cmd` found, once it has run: its exit code, checked against
+/// the plan it saved. Output must have been captured, for `Error`'s stderr.
+pub fn plan_outcome(session: &Session, cmd: &Cmd, ran: &Outcome) -> Result<PlanOutcome, Error> {
+    let code = ran.status.code();
+    // a failed plan may leave an older plan file behind
+    let saved = match code {
+        Some(0) | Some(2) => {
+            saved_plan(session, cmd).context("could not read the plan terraform saved")?
+        }
+        _ => None,
+    };
+    PlanOutcome::new(code, detailed_exitcode(cmd), &ran.stderr, saved)
+}
+
+impl std::fmt::Display for PlanOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlanOutcome::NoChanges => write!(f, "no changes"),
+            PlanOutcome::Changes(plan) => write!(f, "{}", plan.total),
+            PlanOutcome::Error(output) => write!(f, "an error: {}", output),
+        }
+    }
+}
+
+#[test]
+fn test_plan_summary() {
+    let summary = PlanSummary::from_json(
+        r#"{
+  "format_version": "0.1",
+  "resource_changes": [
+    {"address": "module.tectonic-aws.aws_instance.master[0]",
+     "module_address": "module.tectonic-aws", "type": "aws_instance",
+     "change": {"actions": ["delete"]}},
+    {"address": "module.tectonic-aws.aws_instance.worker[0]",
+     "module_address": "module.tectonic-aws", "type": "aws_instance",
+     "change": {"actions": ["delete", "create"]}},
+    {"address": "aws_route53_record.api", "type": "aws_route53_record",
+     "change": {"actions": ["update"]}},
+    {"address": "aws_s3_bucket.assets", "type": "aws_s3_bucket",
+     "change": {"actions": ["no-op"]}}
+  ]
+}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        summary.total,
+        Counts {
+            create: 1,
+            update: 1,
+            delete: 2
+        }
+    );
+    assert_eq!(summary.changes.len(), 3);
+    assert_eq!(summary.by_type["aws_instance"].delete, 2);
+    assert_eq!(summary.by_module["(root)"].update, 1);
+    assert!(!summary.by_type.contains_key("aws_s3_bucket"));
+    assert!(summary
+        .to_string()
+        .starts_with("Plan: 1 to create, 1 to update, 2 to delete\n  by module:\n"));
+
+    assert!(PlanSummary::from_json(r#"{"format_version": "0.1"}"#)
+        .unwrap()
+        .total
+        .is_empty());
+    assert!(PlanSummary::from_json("Error: no plan file").is_err());
+
+    let plan = plan_destroy_with_tfvars_file("/tectonic", "development1", "infra");
+    let show = show_json_for(&plan).unwrap();
+    assert_eq!(show.command_line(), "terraform show -json tfplan.out");
+    assert_eq!(show.working_dir, Some(PathBuf::from("/tectonic")));
+    assert_eq!(show.env.unwrap().borrow()["AWS_PROFILE"], "infra");
+    let show = show_json_for(&apply("/tectonic", "infra")).unwrap();
+    assert_eq!(show.command_line(), "terraform show -json tfplan.out");
+    assert!(show_json_for(&workspace_show("/tectonic", "infra")).is_none());
+}
+
+#[test]
+fn test_check_workspace() {
+    use crate::fake::FakeExecutor;
+    use std::rc::Rc;
+
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("workspace show", 0, "development1");
+    let session = crate::fake::fake_session(&fake);
+    let plan = plan_destroy_with_tfvars_file("/tectonic", "development1", "infra");
+    check_workspace(&session, &plan, "development1").unwrap();
+    let calls = fake.calls();
+    assert_eq!(calls[0].command_line(), "terraform workspace show");
+    assert_eq!(calls[0].working_dir, Some(PathBuf::from("/tectonic")));
+    assert_eq!(
+        calls[0].env.as_ref().unwrap().borrow()["AWS_PROFILE"],
+        "infra"
+    );
+
+    let err = check_workspace(&session, &plan, "production1").unwrap_err();
+    assert!(err.to_string().starts_with(
+        "terraform is on workspace \"development1\" in /tectonic, not \"production1\""
+    ));
+    assert!(uses_workspace(&apply("/tectonic", "infra")));
+    assert!(uses_workspace(&state_rm(
+        "/tectonic",
+        &["module.x"],
+        "infra"
+    )));
+    assert!(!uses_workspace(&get_update("/tectonic")));
+
+    // a dry run only says what it would check
+    let fake = Rc::new(FakeExecutor::new());
+    let session = Session::new(true, None, true, false)
+        .unwrap()
+        .with_executor(fake.clone());
+    check_workspace(&session, &plan, "production1").unwrap();
+    assert!(fake.calls().is_empty());
+}
+
+#[test]
+fn test_plan_outcome() {
+    let plan = |json: &str| Some(PlanSummary::from_json(json).unwrap());
+    let empty = r#"{"format_version": "0.1"}"#;
+    let deletes = r#"{"resource_changes": [{"address": "aws_instance.master",
+        "type": "aws_instance", "change": {"actions": ["delete"]}}]}"#;
+
+    assert!(matches!(
+        PlanOutcome::new(Some(0), true, "", None),
+        Ok(PlanOutcome::NoChanges)
+    ));
+    // an empty -destroy plan may still exit 2
+    assert!(matches!(
+        PlanOutcome::new(Some(2), true, "", plan(empty)),
+        Ok(PlanOutcome::NoChanges)
+    ));
+    match PlanOutcome::new(Some(2), true, "", plan(deletes)).unwrap() {
+        PlanOutcome::Changes(summary) => assert_eq!(summary.total.delete, 1),
+        other => panic!("{}", other),
+    }
+    assert!(matches!(
+        PlanOutcome::new(Some(0), false, "", plan(deletes)),
+        Ok(PlanOutcome::Changes(_))
+    ));
+    match PlanOutcome::new(Some(1), true, "Error: state lock\n", None).unwrap() {
+        PlanOutcome::Error(output) => assert_eq!(output, "Error: state lock"),
+        other => panic!("{}", other),
+    }
+
+    let err = PlanOutcome::new(Some(0), true, "", plan(deletes)).unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("0 to create, 0 to update, 1 to delete"));
+    assert!(PlanOutcome::new(Some(2), true, "", None).is_err());
+}
+
+#[test]
+fn test_run_plan_outcome() {
+    use crate::fake::FakeExecutor;
+    use std::rc::Rc;
+
+    let cmd = plan_destroy_with_tfvars_file("/tectonic", "development1", "infra");
+    let outcome = |code: i32, show: &str| {
+        let fake = Rc::new(FakeExecutor::new());
+        fake.respond("plan", code, "Error: state lock");
+        fake.respond("show -json", 0, show);
+        let session = crate::fake::fake_session(&fake);
+        let ran = session.run("Plan?", &cmd, true).unwrap().unwrap();
+        (plan_outcome(&session, &cmd, &ran), fake.command_lines())
+    };
+    let deletes = r#"{"resource_changes": [{"address": "aws_instance.master",
+        "type": "aws_instance", "change": {"actions": ["delete"]}}]}"#;
+
+    let (found, _) = outcome(2, r#"{"resource_changes": []}"#);
+    assert!(matches!(found, Ok(PlanOutcome::NoChanges)));
+    match outcome(2, deletes).0 {
+        Ok(PlanOutcome::Changes(summary)) => assert_eq!(summary.total.delete, 1),
+        other => panic!("{:?}", other),
+    }
+    // only stderr is kept, and an older plan file is not read
+    let (found, calls) = outcome(1, deletes);
+    match found {
+        Ok(PlanOutcome::Error(stderr)) => assert_eq!(stderr, "Error: state lock"),
+        other => panic!("{:?}", other),
+    }
+    assert!(!calls.iter().any(|c| c.contains("show -json")));
+    let err = outcome(2, "not json").0.unwrap_err();
+    assert!(format!("{:#}", err).starts_with("could not read the plan terraform saved"));
 }
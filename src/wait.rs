@@ -0,0 +1,243 @@
+use crate::runner::{Cmd, CommandExecutor, SystemExecutor};
+use anyhow::Error;
+use console::Term;
+use std::process::Output;
+use std::time::{Duration, Instant};
+
+const SPINNER: [&str; 4] = ["|", "/", "-", "\\"];
+
+/// How long a single check may take before it counts as not ready.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something a workflow waits for before it goes on, e.g. pods coming up.
+/// Each condition is checked with a read-only command. Names may refer to
+/// workflow variables such as `{default_namespace}`.
+#[derive(Clone)]
+pub enum Condition {
+    /// The service `(namespace, name)` has been given a load balancer hostname.
+    LoadBalancer(String, String),
+    /// Every replica of the deployment `(namespace, name)` is up to date.
+    Rollout(String, String),
+    /// The service `(namespace, name)` has at least one ready endpoint.
+    Endpoints(String, String),
+    /// The argocd project exists.
+    ArgoProject(String),
+    /// The command exits successfully.
+    Command(Cmd),
+}
+
+impl Condition {
+    /// The command that checks this condition.
+    pub fn cmd(&self) -> Cmd {
+        let mut cmd = match self {
+            Condition::LoadBalancer(ns, svc) => Cmd::new(vec![
+                "kubectl",
+                "get",
+                "svc",
+                "-n",
+                ns.as_str(),
+                svc.as_str(),
+                "-o",
+                "jsonpath={.status.loadBalancer.ingress[0].hostname}",
+            ]),
+            Condition::Rollout(ns, deployment) => Cmd::new(vec![
+                "kubectl".to_owned(),
+                "rollout".to_owned(),
+                "status".to_owned(),
+                "-n".to_owned(),
+                ns.clone(),
+                format!("deployment/{}", deployment),
+                "--watch=false".to_owned(),
+            ]),
+            Condition::Endpoints(ns, svc) => Cmd::new(vec![
+                "kubectl",
+                "get",
+                "endpoints",
+                "-n",
+                ns.as_str(),
+                svc.as_str(),
+                "-o",
+                "jsonpath={.subsets[*].addresses[*].ip}",
+            ]),
+            Condition::ArgoProject(name) => Cmd::new(vec!["argocd", "proj", "get", name.as_str()]),
+            Condition::Command(cmd) => cmd.clone(),
+        };
+        if cmd.timeout.is_none() {
+            cmd.timeout(CHECK_TIMEOUT);
+        }
+        cmd
+    }
+
+    /// Whether a finished check shows the condition holds.
+    pub fn holds(&self, output: &Output) -> bool {
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        match self {
+            Condition::LoadBalancer(..) | Condition::Endpoints(..) => {
+                output.status.success() && !stdout.trim().is_empty()
+            }
+            // without --watch, rollout status exits 0 while it is still waiting
+            Condition::Rollout(..) => {
+                output.status.success() && stdout.contains("successfully rolled out")
+            }
+            Condition::ArgoProject(_) | Condition::Command(_) => output.status.success(),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Condition::LoadBalancer(ns, svc) => {
+                write!(f, "service {}/{} has a load balancer hostname", ns, svc)
+            }
+            Condition::Rollout(ns, deployment) => {
+                write!(f, "deployment {}/{} has rolled out", ns, deployment)
+            }
+            Condition::Endpoints(ns, svc) => write!(f, "service {}/{} has endpoints", ns, svc),
+            Condition::ArgoProject(name) => write!(f, "argocd project {} exists", name),
+            Condition::Command(cmd) => write!(f, "`{}` succeeds", cmd.command_line()),
+        }
+    }
+}
+
+/// Wait for a condition, checking it every `interval` until `timeout` runs out.
+#[derive(Clone)]
+pub struct Until {
+    pub condition: Condition,
+    pub timeout: Duration,
+    pub interval: Duration,
+}
+
+impl Until {
+    pub fn new(condition: Condition) -> Self {
+        Until {
+            condition,
+            timeout: Duration::from_secs(600),
+            interval: Duration::from_secs(10),
+        }
+    }
+
+    /// Run `cmd`, the filled in check for `what`, until the condition holds or
+    /// the timeout runs out. Returns whether it held. A spinner is shown while
+    /// waiting on a terminal.
+    pub fn poll(
+        &self,
+        what: &str,
+        cmd: &Cmd,
+        executor: &dyn CommandExecutor,
+    ) -> Result<bool, Error> {
+        let term = Term::stdout();
+        let started = Instant::now();
+        if !term.is_term() {
+            println!("Waiting until {} (up to {}s)", what, self.timeout.as_secs());
+        }
+        let mut frame = 0;
+        loop {
+            // a check that cannot run counts as not ready yet
+            if let Ok(output) = executor.output(cmd) {
+                if self.condition.holds(&output) {
+                    if term.is_term() {
+                        term.clear_line()?;
+                    }
+                    println!("Ready after {}s: {}", started.elapsed().as_secs(), what);
+                    return Ok(true);
+                }
+            }
+            let next = Instant::now() + self.interval;
+            loop {
+                if started.elapsed() >= self.timeout {
+                    if term.is_term() {
+                        term.clear_line()?;
+                    }
+                    return Ok(false);
+                }
+                if term.is_term() {
+                    term.clear_line()?;
+                    term.write_str(&format!(
+                        "{} Waiting until {} ({}s)",
+                        SPINNER[frame % SPINNER.len()],
+                        what,
+                        started.elapsed().as_secs()
+                    ))?;
+                    frame += 1;
+                }
+                if Instant::now() >= next {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
+
+/// Split `namespace/name`, as conditions are written in playbooks.
+pub fn namespaced(s: &str) -> Option<(String, String)> {
+    let mut parts = s.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(ns), Some(name)) if !ns.is_empty() && !name.is_empty() => {
+            Some((ns.to_owned(), name.to_owned()))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_poll() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-wait-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // ready on the third check
+    let mut cmd = Cmd::new(vec![
+        "sh",
+        "-c",
+        "n=$(cat count 2>/dev/null || echo 0); echo $((n+1)) > count; [ $n -ge 2 ]",
+    ]);
+    cmd.dir(dir.clone());
+    let mut until = Until::new(Condition::Command(cmd));
+    until.interval = Duration::from_millis(10);
+    assert!(until
+        .poll("ready", &until.condition.cmd(), &SystemExecutor)
+        .unwrap());
+    let count = std::fs::read_to_string(dir.join("count")).unwrap();
+    assert_eq!(count.trim(), "3");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut until = Until::new(Condition::Command(Cmd::new(vec!["false"])));
+    until.interval = Duration::from_millis(10);
+    until.timeout = Duration::from_millis(200);
+    assert!(!until
+        .poll("never", &until.condition.cmd(), &SystemExecutor)
+        .unwrap());
+}
+
+#[test]
+fn test_conditions() {
+    let output = |code: i32, stdout: &str| Output {
+        status: std::os::unix::process::ExitStatusExt::from_raw(code << 8),
+        stdout: stdout.as_bytes().to_vec(),
+        stderr: vec![],
+    };
+    let lb = Condition::LoadBalancer("argocd".to_owned(), "argocd-server".to_owned());
+    assert!(!lb.holds(&output(0, "")));
+    assert!(lb.holds(&output(0, "abc.elb.amazonaws.com")));
+    assert!(!lb.holds(&output(1, "error")));
+
+    let rollout = Condition::Rollout("argocd".to_owned(), "argocd-server".to_owned());
+    assert!(!rollout.holds(&output(
+        0,
+        "Waiting for deployment \"argocd-server\" rollout to finish"
+    )));
+    assert!(rollout.holds(&output(
+        0,
+        "deployment \"argocd-server\" successfully rolled out"
+    )));
+    assert_eq!(
+        rollout.cmd().command_line(),
+        "kubectl rollout status -n argocd deployment/argocd-server --watch=false"
+    );
+
+    assert_eq!(
+        namespaced("{default_namespace}/chartmuseum"),
+        Some(("{default_namespace}".to_owned(), "chartmuseum".to_owned()))
+    );
+    assert_eq!(namespaced("chartmuseum"), None);
+}
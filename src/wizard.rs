@@ -0,0 +1,281 @@
+use crate::check;
+use crate::config::{Cluster, Config};
+use anyhow::{anyhow, Context, Error};
+use console::Term;
+use dialoguer::{theme::ColorfulTheme, Confirmation, Input, Select};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Where checkouts usually live, relative to HOME.
+const CHECKOUT_DIRS: [&str; 5] = ["Code", "code", "src", "go/src/github.com/paperlesspost", ""];
+
+/// Walk through every config.toml setting, offering defaults found on this
+/// machine, and write the result to `path`.
+pub fn run(path: &Path, home: &Path) -> Result<(), Error> {
+    if !Term::stdout().is_term() {
+        return Err(anyhow!("config init asks questions; run it in a terminal"));
+    }
+    let theme = ColorfulTheme::default();
+    if path.exists()
+        && !Confirmation::with_theme(&theme)
+            .with_text(&format!("{} exists. Overwrite it?", path.display()))
+            .default(false)
+            .interact()?
+    {
+        return Ok(());
+    }
+
+    let kbfs = keybase_root();
+    let terraforming_path = ask_path(
+        &theme,
+        "Path to your terraforming checkout",
+        find_checkout(home, "terraforming"),
+        |p| {
+            check::git_checkout(p)?;
+            check::directory(&p.join("projects/kubernetes-tectonic"))
+        },
+    )?;
+    let kubernetes_deployments_path = ask_path(
+        &theme,
+        "Path to your kubernetes-deployments checkout",
+        find_checkout(home, "kubernetes-deployments"),
+        |p| {
+            check::git_checkout(p)?;
+            check::directory(&p.join("charts/pp-argo-cd"))
+        },
+    )?;
+    let kubernetes_deployments_revision = ask(
+        &theme,
+        "Revision of kubernetes-deployments to deploy",
+        "master",
+    )?;
+    let kubernetes_deployments_ssh_key = ask_path(
+        &theme,
+        "Path to the kubernetes-deployments ssh key",
+        kbfs.join("team/paperlesspost.infra.keys/ssh/kubernetes-deployments"),
+        |p| {
+            if p.is_file() {
+                Ok(())
+            } else {
+                Err(format!("{} is not a file", p.display()))
+            }
+        },
+    )?;
+    let keybase_secure_manifests_path = ask_path(
+        &theme,
+        "Path to the secure manifests in keybase",
+        kbfs.join("team/paperlesspost.kubernetes.secure_manifests"),
+        check::mounted,
+    )?;
+
+    let profiles: Vec<String> = check::aws_profiles(&check::aws_config_path(home))
+        .map(|p| p.into_iter().collect())
+        .unwrap_or_default();
+    let infra_profile = ask_profile(
+        &theme,
+        "AWS profile for the infra account",
+        &profiles,
+        "infra",
+    )?;
+    let v1_profile = ask_profile(&theme, "AWS profile for the v1 account", &profiles, "v1")?;
+    let region = ask(&theme, "AWS region of the clusters", "us-east-1")?;
+    let assets_cache_path = ask_path(
+        &theme,
+        "Where to keep downloaded kubeconfigs and run journals",
+        home.join(".config/clusterctl/assets"),
+        check::writable,
+    )?;
+
+    let ids = ask(
+        &theme,
+        "Clusters to operate on, separated by spaces",
+        "development0 development1 development2 production0 production1 production2",
+    )?;
+    let namespaces = ask(
+        &theme,
+        "Namespaces to create next to the default one, separated by spaces",
+        "mars",
+    )?;
+    let mut clusters = BTreeMap::new();
+    for id in ids.split_whitespace() {
+        let environment = match guess_environment(id) {
+            Some(env) => env.to_owned(),
+            None => ask(&theme, &format!("Environment of {}", id), "development")?,
+        };
+        clusters.insert(
+            id.to_owned(),
+            Cluster {
+                environment,
+                default_namespace: None,
+                region: None,
+                aws_profile: None,
+                assets_bucket: None,
+                namespaces: namespaces.split_whitespace().map(str::to_owned).collect(),
+                terraform_workspace: None,
+            },
+        );
+    }
+
+    let conf = Config {
+        version: crate::config::VERSION,
+        terraforming_path,
+        kubernetes_deployments_path,
+        keybase_secure_manifests_path,
+        kubernetes_deployments_revision,
+        kubernetes_deployments_ssh_key,
+        infra_profile,
+        v1_profile,
+        assets_cache_path,
+        region,
+        argocd_insecure: false,
+        clusters,
+        policy: Default::default(),
+        config_dir: PathBuf::new(),
+        sources: BTreeMap::new(),
+    };
+    let toml = toml::to_string(&conf).context("could not write config")?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(
+        path,
+        format!("# Written by clusterctl config init\n\n{}", toml),
+    )
+    .with_context(|| format!("could not write {}", path.display()))?;
+    println!("\nWrote {}\n", path.display());
+
+    let checks = check::check_config(&conf, &check::aws_config_path(home));
+    print!("{}", check::report(&checks));
+    Ok(())
+}
+
+fn ask(theme: &ColorfulTheme, prompt: &str, default: &str) -> Result<String, Error> {
+    let answer = Input::<String>::with_theme(theme)
+        .with_prompt(prompt)
+        .default(default.to_owned())
+        .interact()?;
+    Ok(answer.trim().to_owned())
+}
+
+/// Ask for a path until `valid` accepts it. `~` stands for HOME.
+fn ask_path<V>(
+    theme: &ColorfulTheme,
+    prompt: &str,
+    default: PathBuf,
+    valid: V,
+) -> Result<String, Error>
+where
+    V: Fn(&Path) -> Result<(), String> + 'static,
+{
+    let answer = Input::<String>::with_theme(theme)
+        .with_prompt(prompt)
+        .default(default.to_string_lossy().into_owned())
+        .validate_with(move |s: &str| valid(&expand_home(s.trim())))
+        .interact()?;
+    Ok(expand_home(answer.trim()).to_string_lossy().into_owned())
+}
+
+/// Pick one of the profiles in the aws cli config, or type one in if there
+/// are none.
+fn ask_profile(
+    theme: &ColorfulTheme,
+    prompt: &str,
+    profiles: &[String],
+    account: &str,
+) -> Result<String, Error> {
+    if profiles.is_empty() {
+        return ask(theme, prompt, &format!("{}_power_user", account));
+    }
+    let idx = Select::with_theme(theme)
+        .with_prompt(prompt)
+        .items(profiles)
+        .default(default_profile(profiles, account))
+        .interact()?;
+    Ok(profiles[idx].clone())
+}
+
+/// The index of the profile most likely meant for `account`: its power user
+/// profile, or else the first profile that names it.
+fn default_profile(profiles: &[String], account: &str) -> usize {
+    let power_user = format!("{}_power_user", account);
+    profiles
+        .iter()
+        .position(|p| *p == power_user)
+        .or_else(|| profiles.iter().position(|p| p.contains(account)))
+        .unwrap_or(0)
+}
+
+/// The first common checkout location of `repo` that exists, or else the
+/// first of them.
+fn find_checkout(home: &Path, repo: &str) -> PathBuf {
+    let candidates: Vec<PathBuf> = CHECKOUT_DIRS
+        .iter()
+        .map(|dir| home.join(dir).join(repo))
+        .collect();
+    candidates
+        .iter()
+        .find(|p| p.join(".git").exists())
+        .unwrap_or(&candidates[0])
+        .clone()
+}
+
+/// Where keybase mounts its filesystem on this OS.
+fn keybase_root() -> PathBuf {
+    #[cfg(target_os = "macos")]
+    let candidates = ["/Volumes/Keybase", "/keybase"];
+    #[cfg(not(target_os = "macos"))]
+    let candidates = ["/keybase", "/run/user/keybase/kbfs"];
+    let found = candidates.iter().find(|p| Path::new(p).exists());
+    PathBuf::from(found.unwrap_or(&candidates[0]))
+}
+
+fn guess_environment(cluster_id: &str) -> Option<&'static str> {
+    ["development", "production"]
+        .iter()
+        .find(|env| cluster_id.starts_with(*env))
+        .copied()
+}
+
+fn expand_home(s: &str) -> PathBuf {
+    match (s.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(s),
+    }
+}
+
+#[test]
+fn test_defaults() {
+    let profiles: Vec<String> = ["default", "infra", "v1_power_user", "v1_read_only"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    assert_eq!(default_profile(&profiles, "v1"), 2);
+    assert_eq!(default_profile(&profiles, "infra"), 1);
+    assert_eq!(default_profile(&profiles, "staging"), 0);
+
+    let home = std::env::temp_dir().join(format!("clusterctl-wizard-{}", std::process::id()));
+    assert_eq!(
+        find_checkout(&home, "terraforming"),
+        home.join("Code/terraforming")
+    );
+    std::fs::create_dir_all(home.join("src/terraforming/.git")).unwrap();
+    assert_eq!(
+        find_checkout(&home, "terraforming"),
+        home.join("src/terraforming")
+    );
+    std::fs::remove_dir_all(&home).unwrap();
+
+    assert_eq!(guess_environment("production3"), Some("production"));
+    assert_eq!(guess_environment("staging-eu"), None);
+}
+
+#[test]
+fn test_written_config_parses() {
+    let conf = crate::config::test_config();
+    let written = toml::to_string(&conf).unwrap();
+    // unset cluster settings are left out rather than written empty
+    assert!(!written.contains("aws_profile"));
+    let read: Config = toml::from_str(&written).unwrap();
+    assert_eq!(read.clusters["development1"].namespaces, vec!["mars"]);
+    assert_eq!(read.region, "us-east-1");
+}
@@ -0,0 +1,1291 @@
+use crate::context::ClusterContext;
+use crate::policy::Policy;
+use crate::runner::{self, Cmd, Expect, Outcome, Retry};
+use crate::session::{Choice, Recovery, Session};
+use crate::terraform::{self, Counts, PlanOutcome, PlanSummary};
+use crate::wait::Until;
+use anyhow::{anyhow, Error};
+use console::Style;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Values substituted for `{name}` in step commands and files, such as
+/// `{cluster_id}` or the output of an earlier query step.
+pub type Vars = HashMap<String, String>;
+
+/// What a step does when it is executed.
+#[derive(Clone)]
+pub enum Action {
+    /// Run a command and check its result.
+    Run(Cmd, Expect),
+    /// Run a read-only command quietly and keep its trimmed stdout as `{var}`
+    /// for later steps. A dry run uses `placeholder` instead. A sensitive
+    /// value, such as a password, is never shown.
+    Query {
+        cmd: Cmd,
+        var: String,
+        placeholder: String,
+        sensitive: bool,
+        /// Keep only this field of a JSON object printed by the command.
+        json_field: Option<String>,
+    },
+    /// Write a file that a later step reads.
+    WriteFile { path: PathBuf, contents: String },
+    /// Ask the operator for a secret in their $EDITOR, kept as `{var}`. If the
+    /// operator declines, steps that use `{var}` are skipped. The value is
+    /// never shown.
+    Edit { msg: String, var: String },
+}
+
+/// One named step of a workflow.
+#[derive(Clone)]
+pub struct Step {
+    pub name: String,
+    /// Printed before the step is offered.
+    pub description: Option<String>,
+    pub prompt: String,
+    pub action: Action,
+    /// Skipping a required step ends the workflow, because later steps
+    /// depend on it.
+    pub required: bool,
+    /// Printed when the command does not behave as expected.
+    pub hint: Option<String>,
+    /// Run the command again when it fails in a way that is known to pass.
+    pub retry: Option<Retry>,
+    /// Conditions to wait for once the step has run, e.g. pods coming up.
+    /// The step is not done until they all hold.
+    pub until: Vec<Until>,
+    /// Wait for the operator after the step.
+    pub wait: Option<String>,
+    /// A question the operator must answer yes to after the step runs.
+    pub verify: Option<String>,
+    /// Variables set while this step runs only, e.g. `{namespace}`.
+    pub vars: Vars,
+    /// Summarize the terraform plan the command saves once it has run.
+    pub summarize_plan: bool,
+    /// The plan the command saves must have no changes, e.g. a -destroy
+    /// re-plan once everything has been destroyed.
+    pub expect_empty_plan: bool,
+    /// Flag the terraform plan the command is about to apply if it deletes a
+    /// number of resources outside this range.
+    pub review_plan: Option<RangeInclusive<usize>>,
+}
+
+impl Step {
+    fn new(name: &str, prompt: &str, action: Action) -> Self {
+        Step {
+            name: name.to_owned(),
+            description: None,
+            prompt: prompt.to_owned(),
+            action,
+            required: false,
+            hint: None,
+            retry: None,
+            until: vec![],
+            wait: None,
+            verify: None,
+            vars: Vars::new(),
+            summarize_plan: false,
+            expect_empty_plan: false,
+            review_plan: None,
+        }
+    }
+
+    pub fn run(name: &str, prompt: &str, cmd: Cmd, expect: Expect) -> Self {
+        Step::new(name, prompt, Action::Run(cmd, expect))
+    }
+
+    pub fn query(name: &str, cmd: Cmd, var: &str, placeholder: &str) -> Self {
+        let action = Action::Query {
+            cmd,
+            var: var.to_owned(),
+            placeholder: placeholder.to_owned(),
+            sensitive: false,
+            json_field: None,
+        };
+        Step::new(name, "", action)
+    }
+
+    pub fn write_file(name: &str, path: PathBuf, contents: String) -> Self {
+        Step::new(name, "", Action::WriteFile { path, contents })
+    }
+
+    pub fn edit(name: &str, prompt: &str, msg: &str, var: &str) -> Self {
+        let action = Action::Edit {
+            msg: msg.to_owned(),
+            var: var.to_owned(),
+        };
+        Step::new(name, prompt, action)
+    }
+
+    pub fn describe(mut self, description: &str) -> Self {
+        self.description = Some(description.to_owned());
+        self
+    }
+
+    pub fn required(mut self) -> Self {
+        self.required = true;
+        self
+    }
+
+    pub fn hint(mut self, hint: &str) -> Self {
+        self.hint = Some(hint.to_owned());
+        self
+    }
+
+    /// Never show the value a query step reads.
+    pub fn sensitive(mut self) -> Self {
+        if let Action::Query { sensitive, .. } = &mut self.action {
+            *sensitive = true;
+        }
+        self
+    }
+
+    /// Keep only `field` of the JSON object a query step reads.
+    pub fn json_field(mut self, field: &str) -> Self {
+        if let Action::Query { json_field, .. } = &mut self.action {
+            *json_field = Some(field.to_owned());
+        }
+        self
+    }
+
+    pub fn retry(mut self, retry: Retry) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    pub fn until(mut self, until: Until) -> Self {
+        self.until.push(until);
+        self
+    }
+
+    pub fn wait(mut self, msg: &str) -> Self {
+        self.wait = Some(msg.to_owned());
+        self
+    }
+
+    pub fn verify(mut self, question: &str) -> Self {
+        self.verify = Some(question.to_owned());
+        self
+    }
+
+    /// Summarize the plan a `terraform plan -out` step saves.
+    pub fn summarize_plan(mut self) -> Self {
+        self.summarize_plan = true;
+        self
+    }
+
+    /// Fail unless the plan a `terraform plan -out` step saves is empty.
+    pub fn expect_empty_plan(mut self) -> Self {
+        self.summarize_plan = true;
+        self.expect_empty_plan = true;
+        self
+    }
+
+    /// Flag the plan a `terraform apply` step applies unless it deletes
+    /// `deletes` resources.
+    pub fn review_plan(mut self, deletes: RangeInclusive<usize>) -> Self {
+        self.review_plan = Some(deletes);
+        self
+    }
+
+    /// The command this step runs, before substitution.
+    fn cmd(&self) -> Option<&Cmd> {
+        match &self.action {
+            Action::Run(cmd, _) | Action::Query { cmd, .. } => Some(cmd),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a workflow should go on after a step.
+enum Flow {
+    Continue,
+    Stop,
+}
+
+/// An ordered list of steps, executed one at a time with the operator's
+/// consent. Every subcommand that changes a cluster is a workflow.
+pub struct Workflow {
+    pub name: String,
+    pub steps: Vec<Step>,
+    /// Rules every plan a `terraform apply` step applies must follow.
+    pub policy: Policy,
+}
+
+impl Workflow {
+    pub fn new(name: &str, steps: Vec<Step>) -> Self {
+        Workflow {
+            name: name.to_owned(),
+            steps,
+            policy: Policy::default(),
+        }
+    }
+
+    /// One line per step: its name and what it does.
+    pub fn list(&self) -> String {
+        let mut out = String::new();
+        for step in &self.steps {
+            let what = match &step.action {
+                Action::Run(cmd, _) | Action::Query { cmd, .. } => cmd.command_line(),
+                Action::WriteFile { path, .. } => format!("write {}", path.display()),
+                Action::Edit { var, .. } => format!("enter {{{}}} in $EDITOR", var),
+            };
+            out.push_str(&format!("{:<32} {}\n", step.name, what));
+        }
+        out
+    }
+
+    /// Repeat every step whose name contains `{namespace}` once for each of
+    /// `namespaces`, e.g. `create-{namespace}-namespace` becomes
+    /// `create-mars-namespace`. Without namespaces the step is dropped.
+    pub fn for_each_namespace(&mut self, namespaces: &[String]) {
+        for step in std::mem::take(&mut self.steps) {
+            if !step.name.contains("{namespace}") {
+                self.steps.push(step);
+                continue;
+            }
+            for ns in namespaces {
+                let mut each = step.clone();
+                each.name = step.name.replace("{namespace}", ns);
+                each.prompt = step.prompt.replace("{namespace}", ns);
+                each.vars.insert("namespace".to_owned(), ns.clone());
+                self.steps.push(each);
+            }
+        }
+    }
+
+    /// Variables whose values must be masked wherever commands are shown.
+    fn sensitive_vars(&self) -> Vec<&str> {
+        self.steps
+            .iter()
+            .filter_map(|step| match &step.action {
+                Action::Query {
+                    var,
+                    sensitive: true,
+                    ..
+                }
+                | Action::Edit { var, .. } => Some(var.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Execute every step in order against the cluster `ctx`. Returns false if
+    /// the operator stopped the workflow by skipping a required step.
+    pub fn run(
+        &self,
+        session: &Session,
+        ctx: &ClusterContext,
+        vars: &mut Vars,
+    ) -> Result<bool, Error> {
+        for step in &self.steps {
+            if let Flow::Stop = self.execute(session, ctx, step, vars, true)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Execute only the step called `name`. Query, write and edit steps before
+    /// it are run too, since they only provide values and files that the step
+    /// may use. Fails, naming the step to run first, if the step still lacks
+    /// something an earlier step provides.
+    pub fn run_step(
+        &self,
+        session: &Session,
+        ctx: &ClusterContext,
+        name: &str,
+        vars: &mut Vars,
+    ) -> Result<(), Error> {
+        let idx = self
+            .steps
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} has no step named {:?}; the steps are:\n{}",
+                    self.name,
+                    name,
+                    self.list()
+                )
+            })?;
+        let target = self.steps[idx].cmd();
+        for step in &self.steps[..idx] {
+            let needed = match &step.action {
+                Action::Run(..) => false,
+                // only ask for secrets the step uses
+                Action::Edit { var, .. } => target.is_some_and(|cmd| mentions(cmd, var)),
+                _ => true,
+            };
+            if needed {
+                self.execute(session, ctx, step, vars, false)?;
+            }
+        }
+        self.check_prerequisites(session, idx, vars)?;
+        self.execute(session, ctx, &self.steps[idx], vars, false)?;
+        Ok(())
+    }
+
+    /// Make sure the step at `idx` has every variable it needs, and that any
+    /// file it reads which an earlier command writes is there.
+    fn check_prerequisites(&self, session: &Session, idx: usize, vars: &Vars) -> Result<(), Error> {
+        let step = &self.steps[idx];
+        let earlier = &self.steps[..idx];
+        let cmd = match step.cmd() {
+            Some(cmd) => cmd,
+            None => return Ok(()),
+        };
+        let mut vars = vars.clone();
+        vars.extend(step.vars.clone());
+        let cmd = fill_cmd(cmd, &vars, &[]).map_err(|var| {
+            let provider = earlier.iter().find(|s| match &s.action {
+                Action::Query { var: v, .. } | Action::Edit { var: v, .. } => *v == var,
+                _ => false,
+            });
+            match provider {
+                Some(provider) => anyhow!(
+                    "step {} needs {{{}}}, which step {} did not provide",
+                    step.name,
+                    var,
+                    provider.name
+                ),
+                None => anyhow!(
+                    "step {} needs {{{}}}, which no step before it provides",
+                    step.name,
+                    var
+                ),
+            }
+        })?;
+        if session.dry_run() {
+            return Ok(());
+        }
+        for writer in earlier {
+            let path = match &writer.action {
+                Action::Run(c, _) => c.writes_file.as_ref(),
+                _ => None,
+            };
+            let path = match path.and_then(|p| fill(&p.to_string_lossy(), &vars).ok()) {
+                Some(path) => path,
+                None => continue,
+            };
+            let reads = cmd.command.iter().any(|arg| arg.contains(&path));
+            if reads && !Path::new(&path).exists() {
+                return Err(anyhow!(
+                    "step {} reads {}, which step {} writes; run --step {} first",
+                    step.name,
+                    path,
+                    writer.name,
+                    writer.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn execute(
+        &self,
+        session: &Session,
+        ctx: &ClusterContext,
+        step: &Step,
+        vars: &mut Vars,
+        checkpoint: bool,
+    ) -> Result<Flow, Error> {
+        vars.extend(step.vars.clone());
+        let flow = self.execute_step(session, ctx, step, vars, checkpoint);
+        for name in step.vars.keys() {
+            vars.remove(name);
+        }
+        flow
+    }
+
+    /// Summarize the plan a step is about to apply. A plan that deletes an
+    /// unexpected number of resources is only applied if the operator, or an
+    /// answer given for that very prompt, insists;
+    /// one that breaks policy, or cannot be read, only with --override-policy.
+    fn review_plan(
+        &self,
+        session: &Session,
+        ctx: &ClusterContext,
+        step: &Step,
+        cmd: &Cmd,
+        vars: &mut Vars,
+    ) -> Result<(), Error> {
+        let loud = Style::new().red().bold();
+        let violations = match read_plan(session, cmd, vars) {
+            Ok(None) => return Ok(()),
+            Ok(Some(summary)) => {
+                if let Some(deletes) = step
+                    .review_plan
+                    .as_ref()
+                    .filter(|d| !d.contains(&summary.total.delete))
+                {
+                    let problem = format!(
+                        "this plan deletes {} resources; expected {}",
+                        summary.total.delete,
+                        expected_count(deletes)
+                    );
+                    println!(
+                        "\n{}",
+                        loud.apply_to(format!("!!! WARNING: {} !!!", problem))
+                    );
+                    // --yes and default answers must not wave this through
+                    if !session.insist("Apply it anyway?")? {
+                        return Err(anyhow!("step {} was not run: {}", step.name, problem));
+                    }
+                }
+                self.policy.violations(&summary, ctx)
+            }
+            Err(e) => vec![format!("could not check the plan: {:#}", e)],
+        };
+        if violations.is_empty() {
+            return Ok(());
+        }
+        println!();
+        for violation in &violations {
+            println!(
+                "{}",
+                loud.apply_to(format!("POLICY VIOLATION: {}", violation))
+            );
+        }
+        if !session.override_policy(&violations) {
+            return Err(anyhow!(
+                "step {} breaks policy; pass --override-policy to run it anyway",
+                step.name
+            ));
+        }
+        println!(
+            "{}",
+            loud.apply_to("Overriding policy as asked; this is journaled")
+        );
+        Ok(())
+    }
+
+    fn execute_step(
+        &self,
+        session: &Session,
+        ctx: &ClusterContext,
+        step: &Step,
+        vars: &mut Vars,
+        checkpoint: bool,
+    ) -> Result<Flow, Error> {
+        let sensitive = self.sensitive_vars();
+        let cmd = match step.cmd().map(|c| fill_cmd(c, vars, &sensitive)) {
+            Some(Ok(mut cmd)) => {
+                ctx.apply(&mut cmd);
+                Some(cmd)
+            }
+            Some(Err(var)) if step.required => {
+                return Err(anyhow!(
+                    "{} needs {{{}}}, which was not provided",
+                    step.name,
+                    var
+                ));
+            }
+            Some(Err(var)) => {
+                println!("---\nSkipping {}: {{{}}} was not provided", step.name, var);
+                return Ok(Flow::Continue);
+            }
+            None => None,
+        };
+        if let Some(description) = &step.description {
+            println!("\n{}", fill_shown(description, vars, &sensitive));
+        }
+
+        match &step.action {
+            Action::Run(template, expect) => {
+                let cmd = cmd.unwrap();
+                println!("---\n{}", cmd.describe());
+                if checkpoint {
+                    let key = format!("{}:{}", self.name, step.name);
+                    let done = session.offer(&key, &step.prompt, &template.command_line())?;
+                    if let Some(status) = done {
+                        println!("Already {} in a previous attempt", status);
+                        return Ok(Flow::Continue);
+                    }
+                }
+                if terraform::uses_workspace(&cmd) {
+                    terraform::check_workspace(session, &cmd, &ctx.terraform_workspace)?;
+                }
+                if terraform::is_apply(&cmd) {
+                    self.review_plan(session, ctx, step, &cmd, vars)?;
+                }
+                // prompts may refer to what a plan changes
+                let prompt = fill_shown(&step.prompt, vars, &sensitive);
+                match session.choose(&prompt, &cmd)? {
+                    Choice::Skip if step.required => {
+                        // The workflow ends here; a resumed run should offer
+                        // this step again.
+                        session.record_choice(&prompt, &cmd, Choice::Exit)?;
+                        println!("Stopping: the steps after {} depend on it", step.name);
+                        return Ok(Flow::Stop);
+                    }
+                    Choice::Skip => session.record_choice(&prompt, &cmd, Choice::Skip)?,
+                    Choice::Exit => {
+                        session.record_choice(&prompt, &cmd, Choice::Exit)?;
+                        std::process::exit(1)
+                    }
+                    Choice::Execute => {
+                        let ran = match run_until_settled(session, step, &prompt, &cmd, expect)? {
+                            Settled::Ran(ran) => ran,
+                            Settled::Skipped => {
+                                session.record_choice(&prompt, &cmd, Choice::Skip)?;
+                                return Ok(Flow::Continue);
+                            }
+                        };
+                        if step.summarize_plan {
+                            check_plan(session, step, &cmd, ran.as_ref(), vars)?;
+                        }
+                        if let Some(question) = &step.verify {
+                            if !session.confirm(question)? {
+                                return Err(anyhow!(
+                                    "step {} failed verification: {}",
+                                    step.name,
+                                    question
+                                ));
+                            }
+                        }
+                        wait_until_ready(session, ctx, step, vars, &sensitive)?;
+                        session.finish_step()?;
+                    }
+                }
+            }
+            Action::Query {
+                var,
+                placeholder,
+                sensitive,
+                json_field,
+                ..
+            } => {
+                let mut value = session.query(&cmd.unwrap(), placeholder)?;
+                // a dry run reads the placeholder rather than JSON
+                if let Some(field) = json_field.as_ref().filter(|_| value != *placeholder) {
+                    value = json_string(&value, field).ok_or_else(|| {
+                        anyhow!("{} did not print a {:?} field", step.name, field)
+                    })?;
+                }
+                let shown = if *sensitive { "***" } else { value.as_str() };
+                println!("\nDiscovered {}: {}", var, shown);
+                vars.insert(var.clone(), value);
+            }
+            Action::WriteFile { path, contents } => {
+                let contents = fill(contents, vars).map_err(|var| {
+                    anyhow!("{} needs {{{}}}, which was not provided", step.name, var)
+                })?;
+                session.write_file(path, &contents)?;
+            }
+            Action::Edit { msg, var } => {
+                if session.confirm(&step.prompt)? {
+                    match session.edit(msg)? {
+                        Some(value) => {
+                            vars.insert(var.clone(), value.trim().to_owned());
+                        }
+                        None => return Err(anyhow!("nothing was entered for {{{}}}", var)),
+                    }
+                }
+            }
+        }
+        // a command step has already waited before it was marked done
+        if !matches!(step.action, Action::Run(..)) {
+            wait_until_ready(session, ctx, step, vars, &sensitive)?;
+        }
+
+        if let Some(msg) = &step.wait {
+            session.pause(msg)?;
+        }
+        Ok(Flow::Continue)
+    }
+}
+
+/// How a step's command was left once it settled.
+enum Settled {
+    /// It behaved as expected, or the operator went on anyway. None in a dry
+    /// run.
+    Ran(Option<Outcome>),
+    /// The operator skipped the step.
+    Skipped,
+}
+
+/// Run a step's command until it behaves as expected or its retry policy gives
+/// up, then let the operator decide how to go on.
+fn run_until_settled(
+    session: &Session,
+    step: &Step,
+    prompt: &str,
+    cmd: &Cmd,
+    expect: &Expect,
+) -> Result<Settled, Error> {
+    // a failed plan is reported with what terraform printed
+    let capture = expect.inspects_output()
+        || step.summarize_plan
+        || step.retry.as_ref().is_some_and(|r| r.inspects_output());
+    let mut attempt = 1;
+    loop {
+        let outcome = match session.run(prompt, cmd, capture)? {
+            Some(outcome) => outcome,
+            None => {
+                println!("EXPECT: {}", expect);
+                if let Some(retry) = &step.retry {
+                    println!("RETRY: {}", retry);
+                }
+                return Ok(Settled::Ran(None));
+            }
+        };
+        if !outcome.timed_out && expect.matches(&outcome.status, &outcome.output) {
+            return Ok(Settled::Ran(Some(outcome)));
+        }
+        let failure = if outcome.timed_out {
+            let timeout = cmd.timeout.unwrap_or_default().as_secs();
+            format!("{} timed out after {}s", step.name, timeout)
+        } else {
+            format!("Expected {}, got {}", expect, outcome.status)
+        };
+        println!("\n{}", failure);
+
+        if let Some(retry) = &step.retry {
+            if attempt < retry.attempts && retry.retryable(&outcome.status, &outcome.output) {
+                let delay = retry.delay(attempt);
+                attempt += 1;
+                println!(
+                    "Retrying in {}s (attempt {} of {})",
+                    delay.as_secs(),
+                    attempt,
+                    retry.attempts
+                );
+                std::thread::sleep(delay);
+                println!("---\n{}", cmd.describe());
+                continue;
+            }
+        }
+        if let Some(hint) = &step.hint {
+            println!("{}", hint);
+        }
+        let (msg, options) = if outcome.timed_out {
+            let msg = format!("{}. The command was killed.", failure);
+            (msg, vec![Recovery::Retry, Recovery::Skip, Recovery::Exit])
+        } else {
+            let msg = "Previous command behaved unexpectedly. Proceed with caution.".to_owned();
+            (
+                msg,
+                vec![Recovery::Continue, Recovery::Retry, Recovery::Exit],
+            )
+        };
+        match session.recover(&msg, &options)? {
+            Recovery::Retry => {
+                attempt = 1;
+                println!("---\n{}", cmd.describe());
+            }
+            Recovery::Continue => return Ok(Settled::Ran(Some(outcome))),
+            Recovery::Skip => return Ok(Settled::Skipped),
+            Recovery::Exit if outcome.timed_out => {
+                return Err(anyhow!("step {} timed out", step.name))
+            }
+            Recovery::Exit => return Err(anyhow!("step {} behaved unexpectedly", step.name)),
+        }
+    }
+}
+
+/// Read back the terraform plan that `cmd` saves or applies and show it.
+/// Returns None in a dry run, when there is no plan to read.
+fn read_plan(session: &Session, cmd: &Cmd, vars: &mut Vars) -> Result<Option<PlanSummary>, Error> {
+    let summary = terraform::saved_plan(session, cmd)?;
+    if let Some(summary) = &summary {
+        show_plan(summary, vars);
+    }
+    Ok(summary)
+}
+
+/// Print a plan's summary and keep what it changes for later prompts.
+fn show_plan(summary: &PlanSummary, vars: &mut Vars) {
+    println!("\n{}", summary);
+    plan_vars(&summary.total, vars);
+}
+
+/// Keep what a plan changes as `{plan_changes}`, `{plan_create}`,
+/// `{plan_update}` and `{plan_delete}`.
+fn plan_vars(total: &Counts, vars: &mut Vars) {
+    let counts = [
+        ("plan_changes", total.to_string()),
+        ("plan_create", total.create.to_string()),
+        ("plan_update", total.update.to_string()),
+        ("plan_delete", total.delete.to_string()),
+    ];
+    for (k, v) in counts.iter() {
+        vars.insert(k.to_string(), v.clone());
+    }
+}
+
+/// Work out what a plan step found and hold it to `expect_empty_plan`. A plan
+/// that failed fails the step, even if the operator went on past it.
+fn check_plan(
+    session: &Session,
+    step: &Step,
+    cmd: &Cmd,
+    ran: Option<&Outcome>,
+    vars: &mut Vars,
+) -> Result<(), Error> {
+    let ran = match ran {
+        Some(ran) => ran,
+        None => {
+            read_plan(session, cmd, vars)?;
+            return Ok(());
+        }
+    };
+    let outcome = terraform::plan_outcome(session, cmd, ran)
+        .map_err(|e| anyhow!("step {}: {:#}", step.name, e))?;
+    match outcome {
+        PlanOutcome::NoChanges => {
+            plan_vars(&Counts::default(), vars);
+            println!("\nThe plan has no changes");
+            Ok(())
+        }
+        PlanOutcome::Changes(summary) => {
+            show_plan(&summary, vars);
+            if step.expect_empty_plan {
+                return Err(anyhow!(
+                    "step {} should have found nothing left to change, but found {}",
+                    step.name,
+                    summary.total
+                ));
+            }
+            Ok(())
+        }
+        PlanOutcome::Error(stderr) if stderr.is_empty() => Err(anyhow!(
+            "step {} failed: terraform exited with {}",
+            step.name,
+            ran.status
+        )),
+        PlanOutcome::Error(stderr) => Err(anyhow!("step {} failed: {}", step.name, stderr)),
+    }
+}
+
+fn expected_count(range: &RangeInclusive<usize>) -> String {
+    match (*range.start(), *range.end()) {
+        (start, end) if start == end => start.to_string(),
+        (start, usize::MAX) => format!("at least {}", start),
+        (0, end) => format!("at most {}", end),
+        (start, end) => format!("between {} and {}", start, end),
+    }
+}
+
+/// Wait for each of a step's conditions in turn. When one does not hold in
+/// time, the operator can wait again, go on anyway, or exit.
+fn wait_until_ready(
+    session: &Session,
+    ctx: &ClusterContext,
+    step: &Step,
+    vars: &Vars,
+    sensitive: &[&str],
+) -> Result<(), Error> {
+    for until in &step.until {
+        let what = fill_shown(&until.condition.to_string(), vars, sensitive);
+        // checks run with the step's environment, e.g. credentials
+        let mut check = until.condition.cmd();
+        if let Some(env) = step.cmd().and_then(|cmd| cmd.env.as_ref()) {
+            for (k, v) in env.borrow().iter() {
+                check.env(k, v);
+            }
+        }
+        let mut cmd = fill_cmd(&check, vars, sensitive)
+            .map_err(|var| anyhow!("{} waits on {{{}}}, which was not provided", step.name, var))?;
+        ctx.apply(&mut cmd);
+        loop {
+            match session.wait_until(&what, until, &cmd)? {
+                Some(false) => {}
+                _ => break,
+            }
+            let msg = format!(
+                "Gave up after {}s waiting until {}",
+                until.timeout.as_secs(),
+                what
+            );
+            match session.recover(&msg, &[Recovery::Retry, Recovery::Continue, Recovery::Exit])? {
+                Recovery::Retry => continue,
+                Recovery::Exit => return Err(anyhow!("{}: {}", step.name, msg)),
+                _ => break,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The string `field` of a JSON object.
+fn json_string(json: &str, field: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    value.get(field)?.as_str().map(str::to_owned)
+}
+
+/// Replace every `{name}` in `template` with its value. Braces around anything
+/// but a lowercase identifier, like JSON, are left alone. Returns the name of
+/// the first variable that has no value.
+fn fill(template: &str, vars: &Vars) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(after.len());
+        if name_len > 0 && after[name_len..].starts_with('}') {
+            let name = &after[..name_len];
+            match vars.get(name) {
+                Some(value) => out.push_str(value),
+                None => return Err(name.to_owned()),
+            }
+            rest = &after[name_len + 1..];
+        } else {
+            out.push('{');
+            rest = after;
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Whether a command refers to `{var}` anywhere it is filled in.
+fn mentions(cmd: &Cmd, var: &str) -> bool {
+    let placeholder = format!("{{{}}}", var);
+    let env: Vec<String> = match &cmd.env {
+        Some(env) => env.borrow().values().cloned().collect(),
+        None => vec![],
+    };
+    cmd.command
+        .iter()
+        .chain(cmd.stdin.iter())
+        .chain(env.iter())
+        .any(|s| s.contains(&placeholder))
+}
+
+/// Fill in a description, prompt or condition that is shown or journaled,
+/// leaving unknown variables as they are and masking the values of
+/// `sensitive` variables.
+fn fill_shown(template: &str, vars: &Vars, sensitive: &[&str]) -> String {
+    let filled = fill(template, vars).unwrap_or_else(|_| template.to_owned());
+    let values: Vec<&String> = sensitive
+        .iter()
+        .filter_map(|name| vars.get(*name))
+        .collect();
+    runner::redact(&filled, &values)
+}
+
+/// Fill in a command's variables. The values of `sensitive` variables are
+/// masked when the command is shown.
+fn fill_cmd(cmd: &Cmd, vars: &Vars, sensitive: &[&str]) -> Result<Cmd, String> {
+    let command = cmd
+        .command
+        .iter()
+        .map(|arg| fill(arg, vars))
+        .collect::<Result<Vec<String>, String>>()?;
+    let mut filled = Cmd::new(command);
+    if let Some(env) = &cmd.env {
+        for (k, v) in env.borrow().iter() {
+            filled.env(k, &fill(v, vars)?);
+        }
+    }
+    if let Some(dir) = &cmd.working_dir {
+        filled.dir(PathBuf::from(fill(&dir.to_string_lossy(), vars)?));
+    }
+    if let Some(path) = &cmd.writes_file {
+        filled.writes_file(PathBuf::from(fill(&path.to_string_lossy(), vars)?));
+    }
+    if let Some(data) = &cmd.stdin {
+        filled.stdin(&fill(data, vars)?);
+    }
+    filled.timeout = cmd.timeout;
+    for value in &cmd.sensitive {
+        filled.sensitive(value);
+    }
+    for name in sensitive {
+        if let Some(value) = vars.get(*name) {
+            filled.sensitive(value);
+        }
+    }
+    Ok(filled)
+}
+
+#[test]
+fn test_fill() {
+    let mut vars = Vars::new();
+    vars.insert("cluster_id".to_owned(), "development1".to_owned());
+    vars.insert("default_namespace".to_owned(), "development".to_owned());
+
+    assert_eq!(
+        fill("{cluster_id}.tfvars", &vars),
+        Ok("development1.tfvars".to_owned())
+    );
+    assert_eq!(
+        fill("secrets/{default_namespace}", &vars),
+        Ok("secrets/development".to_owned())
+    );
+    let json = "{ \"data\": { \"x\": \"{cluster_id}\"}}";
+    assert_eq!(
+        fill(json, &vars),
+        Ok("{ \"data\": { \"x\": \"development1\"}}".to_owned())
+    );
+    assert_eq!(fill("automated: {}", &vars), Ok("automated: {}".to_owned()));
+    assert_eq!(fill("{dex_secret}", &vars), Err("dex_secret".to_owned()));
+
+    let mut cmd = Cmd::new(vec!["terraform", "workspace", "select", "{cluster_id}"]);
+    cmd.env("AWS_PROFILE", "infra");
+    cmd.timeout(std::time::Duration::from_secs(300));
+    let filled = fill_cmd(&cmd, &vars, &[]).unwrap();
+    assert_eq!(
+        filled.command_line(),
+        "terraform workspace select development1"
+    );
+    assert_eq!(filled.timeout, cmd.timeout);
+    // the template keeps its own environment
+    filled.env.as_ref().unwrap().borrow_mut().clear();
+    assert_eq!(cmd.env.as_ref().unwrap().borrow().len(), 1);
+
+    vars.insert("argocd_server".to_owned(), "argocd-server-abc12".to_owned());
+    let cmd = Cmd::new(vec!["argocd", "login", "--password", "{argocd_server}"]);
+    let filled = fill_cmd(&cmd, &vars, &["argocd_server", "dex_secret"]).unwrap();
+    assert_eq!(filled.command_line(), "argocd login --password ***");
+    assert_eq!(filled.command[3], "argocd-server-abc12");
+
+    assert_eq!(
+        json_string(r#"{"token": "eyJhbGc"}"#, "token"),
+        Some("eyJhbGc".to_owned())
+    );
+    assert_eq!(json_string("Unauthorized", "token"), None);
+}
+
+#[test]
+fn test_retry() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-retry-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // fails twice, then succeeds
+    let mut cmd = Cmd::new(vec![
+        "sh",
+        "-c",
+        "n=$(cat count 2>/dev/null || echo 0); echo $((n+1)) > count; [ $n -ge 2 ]",
+    ]);
+    cmd.dir(dir.clone());
+    let mut retry = Retry::new(3, std::time::Duration::from_secs(0));
+    retry.codes = vec![1];
+    let workflow = Workflow::new(
+        "test",
+        vec![Step::run("flaky", "Execute?", cmd, Expect::Success).retry(retry)],
+    );
+    let session = Session::new(true, None, false, false).unwrap();
+    let ctx = crate::context::test_context();
+    assert!(workflow.run(&session, &ctx, &mut Vars::new()).unwrap());
+    let count = std::fs::read_to_string(dir.join("count")).unwrap();
+    assert_eq!(count.trim(), "3");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_for_each_namespace() {
+    let mut workflow = Workflow::new(
+        "namespace-init",
+        vec![
+            Step::run(
+                "create-default-namespace",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "create", "ns", "{default_namespace}"]),
+                Expect::Success,
+            ),
+            Step::run(
+                "create-{namespace}-namespace",
+                "Create {namespace}?",
+                Cmd::new(vec!["kubectl", "create", "ns", "{namespace}"]),
+                Expect::Success,
+            ),
+            Step::run(
+                "check-namespace",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "get", "ns", "{namespace}"]),
+                Expect::Success,
+            ),
+        ],
+    );
+    workflow.for_each_namespace(&["mars".to_owned(), "venus".to_owned()]);
+    let names: Vec<&str> = workflow.steps.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(
+        names,
+        vec![
+            "create-default-namespace",
+            "create-mars-namespace",
+            "create-venus-namespace",
+            "check-namespace"
+        ]
+    );
+    assert_eq!(workflow.steps[2].prompt, "Create venus?");
+
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    let session = crate::fake::fake_session(&fake);
+    let mut vars = Vars::new();
+    vars.insert("default_namespace".to_owned(), "development".to_owned());
+    let ctx = crate::context::test_context();
+    assert!(workflow.run(&session, &ctx, &mut vars).unwrap());
+    // {namespace} only has a value in the repeated steps
+    assert_eq!(
+        fake.command_lines(),
+        vec![
+            "kubectl create ns development",
+            "kubectl create ns mars",
+            "kubectl create ns venus",
+        ]
+    );
+}
+
+#[test]
+fn test_sensitive_vars_are_masked() {
+    use crate::wait::{Condition, Until};
+
+    let dir = std::env::temp_dir().join(format!("clusterctl-masked-{}", std::process::id()));
+    let check = Cmd::new(vec!["argocd", "login", "--password", "{password}"]);
+    let workflow = Workflow::new(
+        "test",
+        vec![
+            Step::query(
+                "get-password",
+                Cmd::new(vec!["kubectl", "get", "secret"]),
+                "password",
+                "<password>",
+            )
+            .sensitive(),
+            Step::run(
+                "log-in",
+                "Log in with {password}?",
+                Cmd::new(vec!["argocd", "login", "--password", "{password}"]),
+                Expect::Success,
+            )
+            .describe("Logging in as admin with {password}")
+            .until(Until::new(Condition::Command(check))),
+        ],
+    );
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    fake.respond("get secret", 0, "s3cr3t");
+    let session = crate::fake::fake_session(&fake);
+    session
+        .start_workflow(&dir, "development1", "test")
+        .unwrap();
+    let ctx = crate::context::test_context();
+    assert!(workflow.run(&session, &ctx, &mut Vars::new()).unwrap());
+
+    let (_, id) = crate::journal::list(&dir).unwrap().pop().unwrap();
+    let entries = crate::journal::read(crate::journal::find(&dir, &id).unwrap()).unwrap();
+    let prompts: Vec<&str> = entries.iter().map(|e| e.prompt.as_str()).collect();
+    assert_eq!(
+        prompts,
+        vec![
+            "Log in with ***?",
+            "Wait until `argocd login --password ***` succeeds"
+        ]
+    );
+    let journal = std::fs::read_to_string(crate::journal::find(&dir, &id).unwrap()).unwrap();
+    assert!(!journal.contains("s3cr3t"));
+
+    let mut vars = Vars::new();
+    vars.insert("password".to_owned(), "s3cr3t".to_owned());
+    assert_eq!(
+        fill_shown("Logging in with {password}", &vars, &["password"]),
+        "Logging in with ***"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_missing_var() {
+    let cmd = Cmd::new(vec!["kubectl", "create", "ns", "{namespace}"]);
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    let session = crate::fake::fake_session(&fake);
+    let ctx = crate::context::test_context();
+
+    // an optional step is skipped and the workflow goes on
+    let workflow = Workflow::new(
+        "test",
+        vec![
+            Step::run("create-namespace", "Execute?", cmd.clone(), Expect::Success),
+            Step::run(
+                "list",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "get", "ns"]),
+                Expect::Success,
+            ),
+        ],
+    );
+    assert!(workflow.run(&session, &ctx, &mut Vars::new()).unwrap());
+    assert_eq!(fake.command_lines(), vec!["kubectl get ns"]);
+
+    // a required one ends it before anything that depends on it runs
+    let workflow = Workflow::new(
+        "test",
+        vec![
+            Step::run("create-namespace", "Execute?", cmd, Expect::Success).required(),
+            Step::run(
+                "list",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "get", "ns"]),
+                Expect::Success,
+            ),
+        ],
+    );
+    let err = workflow.run(&session, &ctx, &mut Vars::new()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "create-namespace needs {namespace}, which was not provided"
+    );
+    assert_eq!(fake.command_lines(), vec!["kubectl get ns"]);
+}
+
+#[test]
+fn test_resume_offers_skipped_steps() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-resume-{}", std::process::id()));
+    let step = |name: &str| {
+        let cmd = Cmd::new(vec!["kubectl", "apply", "-f", name]);
+        Step::run(name, &format!("Apply {}?", name), cmd, Expect::Success)
+    };
+    let workflow = Workflow::new("test", vec![step("a"), step("b"), step("c")]);
+    let ctx = crate::context::test_context();
+    let answers: crate::session::Answers = toml::from_str(
+        r#"
+default = "execute"
+
+[steps]
+"Apply b?" = "skip"
+"#,
+    )
+    .unwrap();
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    let session = Session::new(false, Some(answers), false, false)
+        .unwrap()
+        .with_executor(fake.clone());
+    session
+        .start_workflow(&dir, "development1", "test")
+        .unwrap();
+    assert!(workflow.run(&session, &ctx, &mut Vars::new()).unwrap());
+    assert_eq!(
+        fake.command_lines(),
+        vec!["kubectl apply -f a", "kubectl apply -f c"]
+    );
+
+    // the skipped step was not completed, so resuming starts there
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    let session = Session::new(true, None, false, true)
+        .unwrap()
+        .with_executor(fake.clone());
+    session
+        .start_workflow(&dir, "development1", "test")
+        .unwrap();
+    assert!(workflow.run(&session, &ctx, &mut Vars::new()).unwrap());
+    assert_eq!(
+        fake.command_lines(),
+        vec!["kubectl apply -f b", "kubectl apply -f c"]
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_failed_plan_fails_step() {
+    let plan = crate::terraform::plan_with_tfvars_file("/tectonic", "development1", "infra");
+    // even a step that accepts any exit code fails when its plan does
+    let workflow = Workflow::new(
+        "test",
+        vec![Step::run("plan", "Execute?", plan, Expect::Any).summarize_plan()],
+    );
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    fake.respond("workspace show", 0, "development1");
+    fake.respond("plan", 1, "Error: state lock");
+    let session = crate::fake::fake_session(&fake);
+    let ctx = crate::context::test_context();
+    let err = workflow.run(&session, &ctx, &mut Vars::new()).unwrap_err();
+    assert_eq!(err.to_string(), "step plan failed: Error: state lock");
+
+    // a plan that changes something sets the {plan_*} variables
+    fake.respond("plan", 2, "");
+    fake.respond(
+        "show -json",
+        0,
+        r#"{"resource_changes": [{"address": "aws_instance.master",
+        "type": "aws_instance", "change": {"actions": ["create"]}}]}"#,
+    );
+    let mut vars = Vars::new();
+    assert!(workflow.run(&session, &ctx, &mut vars).unwrap());
+    assert_eq!(
+        vars["plan_changes"],
+        "1 to create, 0 to update, 0 to delete"
+    );
+}
+
+#[test]
+fn test_run_step_prerequisites() {
+    let dir = std::env::temp_dir().join(format!("clusterctl-run-step-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("heapster.yaml");
+    let template = dir.join("argocd.yaml");
+    let mut render = Cmd::new(vec!["helm", "template", "charts/pp-argo-cd"]);
+    render.writes_file(template.clone());
+    let workflow = Workflow::new(
+        "test",
+        vec![
+            Step::run("template-argocd", "Execute?", render, Expect::Success),
+            Step::run(
+                "deploy-argocd",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "apply", "-f", template.to_str().unwrap()]),
+                Expect::Success,
+            ),
+            Step::edit("enter-dex-secret", "Enter the secret?", "dex", "dex_secret"),
+            Step::run(
+                "patch-argocd-secret",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "patch", "secret", "{dex_secret}"]),
+                Expect::Success,
+            ),
+            Step::write_file(
+                "write-heapster-app",
+                manifest.clone(),
+                "kind: App".to_owned(),
+            ),
+            Step::run(
+                "deploy-heapster",
+                "Execute?",
+                Cmd::new(vec!["kubectl", "apply", "-f", manifest.to_str().unwrap()]),
+                Expect::Success,
+            ),
+        ],
+    );
+    let answers: crate::session::Answers = toml::from_str(
+        r#"
+default = "execute"
+
+[steps]
+"Enter the secret?" = "skip"
+"#,
+    )
+    .unwrap();
+    let fake = std::rc::Rc::new(crate::fake::FakeExecutor::new());
+    let session = Session::new(false, Some(answers), false, false)
+        .unwrap()
+        .with_executor(fake.clone());
+    let ctx = crate::context::test_context();
+    let run_step = |name: &str| workflow.run_step(&session, &ctx, name, &mut Vars::new());
+
+    // earlier write steps run first
+    run_step("deploy-heapster").unwrap();
+    assert_eq!(std::fs::read_to_string(&manifest).unwrap(), "kind: App");
+    assert!(fake
+        .command_lines()
+        .last()
+        .unwrap()
+        .starts_with("kubectl apply -f"));
+
+    let err = run_step("patch-argocd-secret").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "step patch-argocd-secret needs {dex_secret}, which step enter-dex-secret did not provide"
+    );
+    let err = run_step("deploy-argocd").unwrap_err();
+    assert!(err
+        .to_string()
+        .ends_with("which step template-argocd writes; run --step template-argocd first"));
+    run_step("template-argocd").unwrap();
+    run_step("deploy-argocd").unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,383 @@
+use crate::config::Config;
+use crate::context::ClusterContext;
+#[cfg(test)]
+use crate::fake::{fake_session, FakeExecutor};
+use crate::playbook;
+use crate::runner::{Cmd, Expect};
+use crate::session::Session;
+use crate::terraform;
+use crate::workflow::{Step, Vars, Workflow};
+use anyhow::Error;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+/// Load a workflow by subcommand name. Launch and init workflows come from
+/// playbooks; destroying things stays built in.
+pub fn load(name: &str, conf: &Config) -> Result<Option<Workflow>, Error> {
+    if let Some(workflow) = playbook::load(name, conf)? {
+        return Ok(Some(workflow));
+    }
+    match name {
+        "destroy-kubernetes-ingress" => Ok(Some(destroy_kubernetes_ingress(conf))),
+        "destroy-cluster" => Ok(Some(destroy_cluster(conf))),
+        _ => Ok(None),
+    }
+}
+
+/// `terraform plan -detailed-exitcode` exits 2 when there is a diff, so only
+/// exit code 1 is a failure. Whether there really is a diff is read from the
+/// saved plan, since a -destroy plan can exit 2 with nothing left to destroy.
+fn plan_succeeded() -> Expect {
+    Expect::Codes(vec![0, 2])
+}
+
+/// Kill a terraform command that hangs, e.g. waiting on a state lock.
+fn timed(mut cmd: Cmd, minutes: u64) -> Cmd {
+    cmd.timeout(Duration::from_secs(minutes * 60));
+    cmd
+}
+
+pub fn destroy_kubernetes_ingress(conf: &Config) -> Workflow {
+    let path = Path::new(&conf.terraforming_path).join("projects/kubernetes-ingress");
+    // the cluster's v1 profile
+    let v1_profile = "{v1_profile}";
+
+    Workflow::new(
+        "destroy-kubernetes-ingress",
+        vec![
+            Step::run(
+                "select-workspace",
+                "Execute command?",
+                timed(terraform::workspace_select(&path, "{terraform_workspace}", v1_profile), 5),
+                Expect::Success,
+            )
+            .describe(
+                "We will now step through destroying the kubernetes-ingress project\n\
+                 First, we must select the right workspace",
+            )
+            .required(),
+            Step::run(
+                "plan-destroy",
+                "Execute command?",
+                timed(terraform::plan_destroy_with_tfvars_file(&path, "{cluster_id}", v1_profile), 30),
+                plan_succeeded(),
+            )
+            .describe(
+                "We will now prepare a -destroy plan against terraforming/projects/kubernetes-ingress",
+            )
+            .summarize_plan()
+            .required(),
+            Step::run(
+                "apply-destroy",
+                "Destroy {plan_delete} resources?",
+                timed(terraform::apply(&path, v1_profile), 30),
+                Expect::Success,
+            )
+            .describe("We are ready to apply. This will DESTROY DNS routes that point to {cluster_id}")
+            // the ingress is a handful of DNS records
+            .review_plan(1..=10)
+            .required(),
+        ],
+    )
+}
+
+pub fn destroy_cluster(conf: &Config) -> Workflow {
+    let path = Path::new(&conf.terraforming_path).join("projects/kubernetes-tectonic");
+    // the cluster's own profile, if it has one
+    let infra_profile = "{infra_profile}";
+
+    // destroy kubernetes-alarms
+    // TODO
+
+    Workflow::new(
+        "destroy-cluster",
+        vec![
+            Step::run(
+                "select-workspace",
+                "Execute command?",
+                timed(terraform::workspace_select(&path, "{terraform_workspace}", infra_profile), 5),
+                Expect::Success,
+            )
+            .describe(
+                "We will now prepare a -destroy plan against terraforming/projects/kubernetes-tectonic\n\
+                 First, we must select the right workspace",
+            )
+            .required(),
+            Step::run(
+                "remove-problem-state",
+                "Execute command or skip?",
+                // state rm waits on the state lock like any other command
+                timed(
+                    terraform::state_rm(
+                        &path,
+                        &[
+                            "module.tectonic-aws.module.bootkube.template_dir.bootkube",
+                            "module.tectonic-aws.module.tectonic.template_dir.tectonic",
+                            "module.tectonic-aws.module.bootkube.template_dir.bootkube_bootstrap",
+                        ],
+                        infra_profile,
+                    ),
+                    5,
+                ),
+                Expect::Success,
+            )
+            .describe("Next, we can optionally remove state that sometimes causes problems"),
+            Step::run(
+                "plan-destroy",
+                "Execute command?",
+                timed(terraform::plan_destroy_with_tfvars_file(&path, "{cluster_id}", infra_profile), 30),
+                plan_succeeded(),
+            )
+            .describe("Next, we actually plan")
+            .hint("You probably need to re-run this tool and remove problematic bootkube/tectonic state.")
+            .summarize_plan()
+            .required(),
+            Step::run(
+                "apply-destroy",
+                "Destroy {plan_delete} resources?",
+                timed(terraform::apply(&path, infra_profile), 90),
+                Expect::Any,
+            )
+            .describe(
+                "We are ready to destroy the cluster. THERE IS NO GOING BACK\n\
+                 If terraform apply encounters an error, this is expected.",
+            )
+            // a cluster is about 120 resources
+            .review_plan(100..=140)
+            .required(),
+            // TODO do we need TWO re-plan and re-applies?
+            Step::run(
+                "replan-destroy",
+                "Execute command?",
+                timed(terraform::plan_destroy_with_tfvars_file(&path, "{cluster_id}", infra_profile), 30),
+                plan_succeeded(),
+            )
+            .describe(
+                "We will now create another -destroy plan to ensure all resources are cleaned up\n\
+                 This plan must show no diff",
+            )
+            .expect_empty_plan()
+            .required(),
+        ],
+    )
+}
+
+#[test]
+fn test_load_workflows() {
+    let conf = crate::config::test_config();
+    for name in &[
+        "argo-init",
+        "namespace-init",
+        "launch-cluster",
+        "destroy-kubernetes-ingress",
+        "destroy-cluster",
+    ] {
+        let workflow = load(name, &conf).unwrap().unwrap();
+        assert_eq!(&workflow.name, name);
+        let mut names: Vec<&str> = workflow.steps.iter().map(|s| s.name.as_str()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), workflow.steps.len(), "{}", name);
+    }
+}
+
+/// `terraform show -json` of a plan that deletes `n` instances.
+fn destroy_plan(n: usize) -> String {
+    let changes: Vec<String> = (0..n)
+        .map(|i| {
+            format!(
+                r#"{{"address": "module.tectonic-aws.aws_instance.worker[{}]", "module_address": "module.tectonic-aws", "type": "aws_instance", "change": {{"actions": ["delete"]}}}}"#,
+                i
+            )
+        })
+        .collect();
+    format!(r#"{{"resource_changes": [{}]}}"#, changes.join(", "))
+}
+
+#[test]
+fn test_destroy_kubernetes_ingress_commands() {
+    let conf = crate::config::test_config();
+    let mut ctx = ClusterContext::new(&conf, "development1").unwrap();
+    ctx.v1_profile = "v1_power_user".to_owned();
+    let mut vars = ctx.vars();
+
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan -out", 2, "");
+    fake.respond("show -json", 0, &destroy_plan(2));
+    fake.respond("workspace show", 0, "development1");
+    let session = fake_session(&fake);
+    assert!(destroy_kubernetes_ingress(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap());
+    assert!(fake
+        .command_lines()
+        .contains(&"terraform apply tfplan.out".to_owned()));
+    // the profile comes from the cluster's context, not the config
+    for cmd in fake.calls() {
+        assert_eq!(cmd.env.unwrap().borrow()["AWS_PROFILE"], "v1_power_user");
+    }
+
+    // a plan that deletes far more than the ingress is only applied on request
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan -out", 2, "");
+    fake.respond("show -json", 0, &destroy_plan(120));
+    fake.respond("workspace show", 0, "development1");
+    let err = destroy_kubernetes_ingress(&conf)
+        .run(&fake_session(&fake), &ctx, &mut vars)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("\"Apply it anyway?\" needs an operator"));
+    assert!(!fake.command_lines().iter().any(|c| c.contains("apply")));
+
+    let answers: crate::session::Answers = toml::from_str(
+        r#"
+default = "execute"
+
+[steps]
+"Apply it anyway?" = "skip"
+"#,
+    )
+    .unwrap();
+    let session = Session::new(false, Some(answers), false, false)
+        .unwrap()
+        .with_executor(fake.clone());
+    let err = destroy_kubernetes_ingress(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("deletes 120 resources; expected between 1 and 10"));
+    assert!(!fake.command_lines().iter().any(|c| c.contains("apply")));
+}
+
+#[test]
+fn test_destroy_cluster_commands() {
+    let conf = crate::config::test_config();
+    let ctx = ClusterContext::new(&conf, "development1").unwrap();
+    let mut vars = ctx.vars();
+
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan", 2, "");
+    // nothing is left for the re-plan to destroy
+    fake.respond("show -json", 0, &destroy_plan(0));
+    fake.respond_once("show -json", 0, &destroy_plan(120));
+    fake.respond_once("show -json", 0, &destroy_plan(120));
+    fake.respond("workspace show", 0, "development1");
+    let session = fake_session(&fake);
+    assert!(destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap());
+    assert_eq!(
+        fake.command_lines(),
+        vec![
+            "terraform workspace select development1",
+            "terraform workspace show",
+            "terraform state rm \
+             module.tectonic-aws.module.bootkube.template_dir.bootkube \
+             module.tectonic-aws.module.tectonic.template_dir.tectonic \
+             module.tectonic-aws.module.bootkube.template_dir.bootkube_bootstrap",
+            "terraform workspace show",
+            "terraform plan -out tfplan.out -var-file development1.tfvars -destroy -detailed-exitcode",
+            "terraform show -json tfplan.out",
+            "terraform workspace show",
+            "terraform show -json tfplan.out",
+            "terraform apply tfplan.out",
+            "terraform workspace show",
+            "terraform plan -out tfplan.out -var-file development1.tfvars -destroy -detailed-exitcode",
+            "terraform show -json tfplan.out",
+        ]
+    );
+    for cmd in fake.calls() {
+        assert_eq!(
+            cmd.working_dir,
+            Some(PathBuf::from("/terraforming/projects/kubernetes-tectonic"))
+        );
+        assert_eq!(cmd.env.unwrap().borrow()["AWS_PROFILE"], "infra");
+    }
+    // every step that takes the state lock can time out
+    for call in fake.calls().iter().filter(|c| {
+        let line = c.command_line();
+        line.contains(" state ") || line.contains(" plan ") || line.contains(" apply ")
+    }) {
+        assert!(call.timeout.is_some(), "{}", call.command_line());
+    }
+
+    // a failed plan stops the workflow before anything is applied
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan", 1, "Error: state lock");
+    fake.respond("workspace show", 0, "development1");
+    let session = fake_session(&fake);
+    assert!(destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .is_err());
+    assert!(!fake.command_lines().iter().any(|c| c.contains("apply")));
+
+    // resources left behind after the apply fail the re-plan
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan", 2, "");
+    fake.respond("show -json", 0, &destroy_plan(120));
+    fake.respond("workspace show", 0, "development1");
+    let session = fake_session(&fake);
+    let err = destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "step replan-destroy should have found nothing left to change, \
+         but found 0 to create, 0 to update, 120 to delete"
+    );
+
+    // nothing is touched on another cluster's workspace
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("workspace show", 0, "production1");
+    let session = fake_session(&fake);
+    let err = destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap_err();
+    assert!(err.to_string().contains("not \"development1\""));
+    assert_eq!(
+        fake.command_lines().last().unwrap(),
+        "terraform workspace show"
+    );
+
+    // a plan that deletes far less than a cluster is only applied on request
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan", 2, "");
+    fake.respond("show -json", 0, &destroy_plan(3));
+    fake.respond("workspace show", 0, "development1");
+    let answers: crate::session::Answers = toml::from_str(
+        r#"
+default = "execute"
+
+[steps]
+"Apply it anyway?" = "skip"
+"#,
+    )
+    .unwrap();
+    let session = Session::new(false, Some(answers), false, false)
+        .unwrap()
+        .with_executor(fake.clone());
+    let err = destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("deletes 3 resources; expected between 100 and 140"));
+    assert!(!fake.command_lines().iter().any(|c| c.contains("apply")));
+
+    // --yes does not answer for a flagged plan
+    let fake = Rc::new(FakeExecutor::new());
+    fake.respond("plan", 2, "");
+    fake.respond("show -json", 0, &destroy_plan(3));
+    fake.respond("workspace show", 0, "development1");
+    let session = fake_session(&fake);
+    let err = destroy_cluster(&conf)
+        .run(&session, &ctx, &mut vars)
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("\"Apply it anyway?\" needs an operator"));
+    assert!(!fake.command_lines().iter().any(|c| c.contains("apply")));
+}
@@ -0,0 +1,379 @@
+//! End-to-end tests. clusterctl runs each workflow non-interactively with fake
+//! `terraform`, `kubectl`, `aws`, `helm`, `argocd`, `curl` and `git`
+//! executables first on PATH. The fakes log their arguments, environment and
+//! stdin, and the log is compared with a snapshot in `tests/snapshots`. Run
+//! with `UPDATE_SNAPSHOTS=1` to rewrite the snapshots after an intended change.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+const TOOLS: [&str; 7] = [
+    "terraform",
+    "kubectl",
+    "aws",
+    "helm",
+    "argocd",
+    "curl",
+    "git",
+];
+
+/// Logs every call to `$CALLS`, then prints canned output.
+const SHIM: &str = r#"#!/bin/sh
+tool=$(basename "$0")
+input=$(cat)
+{
+    printf '%s' "$tool"
+    for arg in "$@"; do printf ' %s' "$arg"; done
+    printf '\n    cwd=%s\n' "$PWD"
+    for var in AWS_PROFILE KUBECONFIG ARGOCD_SERVER ARGOCD_AUTH_TOKEN ARGOCD_OPTS; do
+        eval "value=\${$var}"
+        [ -n "$value" ] && printf '    %s=%s\n' "$var" "$value"
+    done
+    [ -n "$input" ] && printf '    stdin=%s\n' "$input"
+} >> "$CALLS"
+
+case "$tool $*" in
+    "aws s3api list-buckets"*) echo "adevelopment1-assets-4f2a unrelated-bucket" ;;
+    "aws s3api get-object"*) for last; do :; done; echo "apiVersion: v1" > "$last" ;;
+    "kubectl get pod"*"component=server"*) echo "argocd-server-6d8f7b9c4-x2x7z" ;;
+    "kubectl get svc"*"ingress[0].hostname"*) echo "a1b2c3.us-east-1.elb.amazonaws.com" ;;
+    "kubectl get endpoints"*) echo "10.2.3.4" ;;
+    "kubectl rollout status"*) echo 'deployment "argocd-server" successfully rolled out' ;;
+    "helm template"*) echo "kind: Fake" ;;
+    # the deployments checkout is clean and already at the revision
+    "git rev-parse"*) echo "4f2a9c1e0b7d" ;;
+    "curl"*"/api/v1/session") echo '{"token":"fake-token"}' ;;
+    "kubectl create -n "*"-Rf "*/shared) ;;
+    "kubectl create -n "*"-Rf "*)
+        echo 'Error from server (AlreadyExists): error when creating: already exists' >&2
+        exit 1 ;;
+    "terraform workspace show"*) echo "development1" ;;
+    # plans are saved where terraform saves them; once a -destroy plan has
+    # been applied, there is nothing left to destroy
+    "terraform plan"*)
+        if [ -e .destroyed ]; then
+            echo '{"resource_changes": []}' > tfplan.out
+        elif [ -n "$(echo "$*" | grep -- ' -destroy')" ]; then
+            # the ingress is a few DNS records; a cluster is about 120
+            # resources, unless a test says otherwise
+            case "$PWD" in
+                */kubernetes-ingress) n=4 ;;
+                *) n=$(cat "$CALLS.plan-deletes" 2>/dev/null || echo 120) ;;
+            esac
+            i=0
+            sep=
+            printf '{"resource_changes": [' > tfplan.out
+            while [ $i -lt $n ]; do
+                printf '%s{"address": "module.tectonic-aws.aws_instance.worker[%s]", "module_address": "module.tectonic-aws", "type": "aws_instance", "change": {"actions": ["delete"], "before": {"tags": {"KubernetesCluster": "development1"}}}}' "$sep" $i >> tfplan.out
+                sep=', '
+                i=$((i+1))
+            done
+            echo ']}' >> tfplan.out
+        else
+            echo '{"resource_changes": [{"address": "module.tectonic-aws.aws_instance.master[0]", "module_address": "module.tectonic-aws", "type": "aws_instance", "change": {"actions": ["create"]}}]}' > tfplan.out
+        fi
+        # like terraform 0.11, a -destroy plan exits 2 even when it is empty
+        case "$*" in *-detailed-exitcode*) exit 2 ;; esac ;;
+    "terraform show -json"*) cat "$3" ;;
+    # launching a cluster, the first apply is expected to fail
+    "terraform apply"*)
+        if [ -e "$CALLS.fail-apply" ]; then
+            rm "$CALLS.fail-apply"
+            echo "Error applying plan" >&2
+            exit 1
+        fi
+        if grep -q '"delete"' "$2"; then touch .destroyed; fi ;;
+esac
+exit 0
+"#;
+
+/// Answer every prompt with execute, except entering the dex secret, which
+/// needs an editor.
+const ANSWERS: &str = r#"
+default = "execute"
+
+[steps]
+"Open buffer in your $EDITOR to input the secret?" = "skip"
+"#;
+
+struct Harness {
+    root: PathBuf,
+}
+
+impl Harness {
+    fn new(name: &str) -> Self {
+        let root =
+            std::env::temp_dir().join(format!("clusterctl-test-{}-{}", name, std::process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root).unwrap();
+        }
+        for dir in &[
+            "bin",
+            "terraforming/projects/kubernetes-tectonic",
+            "terraforming/projects/kubernetes-ingress",
+            "kubernetes-deployments/charts/pp-argo-cd",
+            "secure",
+            "assets",
+        ] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        // the shims see the real path, e.g. /private/var rather than /var
+        let root = fs::canonicalize(&root).unwrap();
+        for tool in TOOLS.iter() {
+            let path = root.join("bin").join(tool);
+            fs::write(&path, SHIM).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let config = format!(
+            r#"
+version = 2
+terraforming_path = "{root}/terraforming"
+kubernetes_deployments_path = "{root}/kubernetes-deployments"
+kubernetes_deployments_revision = "master"
+kubernetes_deployments_ssh_key = "{root}/id_rsa"
+keybase_secure_manifests_path = "{root}/secure"
+infra_profile = "infra_power_user"
+v1_profile = "v1_power_user"
+assets_cache_path = "{root}/assets"
+
+[clusters.development1]
+environment = "development"
+namespaces = ["mars"]
+"#,
+            root = root.display()
+        );
+        fs::write(root.join("config.toml"), config).unwrap();
+        fs::write(root.join("answers.toml"), ANSWERS).unwrap();
+        Harness { root }
+    }
+
+    /// Run clusterctl with `args` after the global flags.
+    fn run(&self, args: &[&str]) -> Output {
+        let output = self.try_run(args);
+        assert!(
+            output.status.success(),
+            "clusterctl {:?} failed\n{}{}",
+            args,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Run clusterctl with the answers file, whether or not it succeeds.
+    fn try_run(&self, args: &[&str]) -> Output {
+        self.clusterctl()
+            .arg("--answers")
+            .arg(self.root.join("answers.toml"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+
+    /// clusterctl with the test's config and fake tools, and no answers.
+    fn clusterctl(&self) -> Command {
+        let path = format!(
+            "{}:{}",
+            self.root.join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_clusterctl"));
+        cmd.arg("--config")
+            .arg(self.root.join("config.toml"))
+            .current_dir(&self.root)
+            .env("PATH", path)
+            .env("HOME", &self.root)
+            .env("CALLS", self.root.join("calls.log"))
+            .env_remove("KUBECONFIG")
+            .stdin(Stdio::null());
+        cmd
+    }
+
+    /// Every call made to the fake tools, with the test's root directory
+    /// replaced by `$ROOT`.
+    fn calls(&self) -> String {
+        let calls = fs::read_to_string(self.root.join("calls.log")).unwrap_or_default();
+        calls.replace(&self.root.display().to_string(), "$ROOT")
+    }
+
+    fn assert_snapshot(&self, name: &str) {
+        let actual = self.calls();
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/snapshots")
+            .join(format!("{}.calls", name));
+        if std::env::var("UPDATE_SNAPSHOTS").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &actual).unwrap();
+            return;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|_| panic!("no snapshot {:?}; run with UPDATE_SNAPSHOTS=1", path));
+        if actual != expected {
+            panic!(
+                "calls differ from {:?} (run with UPDATE_SNAPSHOTS=1 to accept)\n{}",
+                path,
+                diff(&expected, &actual)
+            );
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A line-by-line diff, good enough to spot what changed.
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => out.push_str(&format!("  {}\n", e)),
+            (e, a) => {
+                if let Some(e) = e {
+                    out.push_str(&format!("- {}\n", e));
+                }
+                if let Some(a) = a {
+                    out.push_str(&format!("+ {}\n", a));
+                }
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_launch_cluster() {
+    let h = Harness::new("launch-cluster");
+    fs::write(h.root.join("calls.log.fail-apply"), "").unwrap();
+    h.run(&["launch-cluster", "--cluster", "development1"]);
+    h.assert_snapshot("launch-cluster");
+}
+
+#[test]
+fn test_namespace_init() {
+    let h = Harness::new("namespace-init");
+    h.run(&["namespace-init", "--cluster", "development1"]);
+    h.assert_snapshot("namespace-init");
+}
+
+#[test]
+fn test_argo_init() {
+    let h = Harness::new("argo-init");
+    let output = h.run(&["argo-init", "--cluster", "development1"]);
+    h.assert_snapshot("argo-init");
+    // the admin password and session token never show up on screen
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("argocd-server-6d8f7b9c4-x2x7z"));
+    assert!(!stdout.contains("fake-token"));
+}
+
+#[test]
+fn test_destroy_cluster() {
+    let h = Harness::new("destroy-cluster");
+    let output = h.run(&["destroy-cluster", "--cluster", "development1"]);
+    h.assert_snapshot("destroy-cluster");
+    // the re-plan exits 2, but its plan is verified empty
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("The plan has no changes"), "{}", stdout);
+}
+
+#[test]
+fn test_policy_blocks_apply() {
+    let h = Harness::new("policy");
+    let dir = h.root.join("terraforming/clusterctl");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(
+        dir.join("policy.toml"),
+        "cluster_tag = \"KubernetesCluster\"\nmax_deletes = { development = 0 }\n",
+    )
+    .unwrap();
+
+    let output = h.try_run(&["destroy-cluster", "--cluster", "development1"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("POLICY VIOLATION: the plan deletes 120 resources"));
+    assert!(!h.calls().contains("terraform apply"));
+
+    h.run(&[
+        "--override-policy",
+        "destroy-cluster",
+        "--cluster",
+        "development1",
+    ]);
+    assert!(h.calls().contains("terraform apply"));
+    let runs = h.run(&["runs", "list"]);
+    let last = String::from_utf8_lossy(&runs.stdout)
+        .lines()
+        .last()
+        .unwrap()
+        .split_whitespace()
+        .last()
+        .unwrap()
+        .to_owned();
+    let journal = h.run(&["runs", "show", &last]);
+    assert!(String::from_utf8_lossy(&journal.stdout).contains(
+        "overrode policy: the plan deletes 120 resources; development plans may delete at most 0"
+    ));
+}
+
+#[test]
+fn test_flagged_plan_needs_explicit_answer() {
+    let h = Harness::new("flagged-plan");
+    fs::write(h.root.join("calls.log.plan-deletes"), "1").unwrap();
+
+    let output = h
+        .clusterctl()
+        .args(["--yes", "destroy-cluster", "--cluster", "development1"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("WARNING: this plan deletes 1 resources; expected between 100 and 140"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("\"Apply it anyway?\" needs an operator"),
+        "{}",
+        stderr
+    );
+    assert!(!h.calls().contains("terraform apply"));
+
+    // a default answer does not cover it either
+    let output = h.try_run(&["destroy-cluster", "--cluster", "development1"]);
+    assert!(!output.status.success());
+    assert!(!h.calls().contains("terraform apply"));
+
+    fs::write(
+        h.root.join("answers.toml"),
+        format!("{}\"Apply it anyway?\" = \"execute\"\n", ANSWERS),
+    )
+    .unwrap();
+    h.run(&["destroy-cluster", "--cluster", "development1"]);
+    assert!(h.calls().contains("terraform apply"));
+}
+
+#[test]
+fn test_dry_run() {
+    let h = Harness::new("dry-run");
+    let output = h.run(&["--dry-run", "namespace-init", "--cluster", "development1"]);
+    assert!(!h.root.join("assets/development1").exists());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("CREATES:"));
+
+    let output = h.run(&["--dry-run", "destroy-cluster", "--cluster", "development1"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("would check workspace is development1"));
+    assert!(!stdout.contains("On terraform workspace"));
+    assert_eq!(h.calls(), "");
+}
+
+#[test]
+fn test_destroy_kubernetes_ingress() {
+    let h = Harness::new("destroy-kubernetes-ingress");
+    h.run(&["destroy-kubernetes-ingress", "--cluster", "development1"]);
+    h.assert_snapshot("destroy-kubernetes-ingress");
+}